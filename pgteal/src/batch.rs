@@ -0,0 +1,57 @@
+use shared::Input;
+use tealr::{
+    mlu::mlua::{self, FromLua, IntoLua, Value},
+    ToTypename, Type,
+};
+
+use crate::{connection::QueryParamCollection, named_params::rewrite_named_params, pg_row::LuaRow};
+
+///A single statement of `Connection:batch`, given as `{sql, params}`.
+pub(crate) struct BatchStatement {
+    pub(crate) sql: String,
+    pub(crate) params: Option<QueryParamCollection>,
+}
+
+impl BatchStatement {
+    ///Statements without a params table can be sent together with the ones next to them as a single simple query.
+    pub(crate) fn can_be_grouped(&self) -> bool {
+        self.params.is_none() && rewrite_named_params(&self.sql).1.is_empty()
+    }
+}
+
+impl ToTypename for BatchStatement {
+    fn to_typename() -> Type {
+        Vec::<Input>::to_typename()
+    }
+}
+
+impl FromLua for BatchStatement {
+    fn from_lua(value: Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        let table = mlua::Table::from_lua(value, lua)?;
+        let sql = table.get(1)?;
+        let params = table.get(2)?;
+        Ok(Self { sql, params })
+    }
+}
+
+///The result of a single statement of `Connection:batch`.
+pub(crate) enum BatchResult {
+    ///The statement does not return rows, so only the amount of affected rows is known
+    Affected(u64),
+    Rows(Vec<LuaRow>),
+}
+
+impl ToTypename for BatchResult {
+    fn to_typename() -> Type {
+        Type::Or(vec![u64::to_typename(), Vec::<LuaRow>::to_typename()])
+    }
+}
+
+impl IntoLua for BatchResult {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<Value> {
+        match self {
+            BatchResult::Affected(x) => x.into_lua(lua),
+            BatchResult::Rows(x) => x.into_lua(lua),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use sqlx::{
+    postgres::{PgConnectOptions, PgSslMode},
+    ConnectOptions as _,
+};
+use tealr::{
+    mlu::mlua::{self, FromLua, Value},
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+
+use crate::pool_options::seconds_to_duration;
+
+fn invalid_connection_string(x: sqlx::Error) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from: "string",
+        to: "ConnectOptions".into(),
+        message: Some(format!("Could not parse the connection string. {x}")),
+    }
+}
+
+fn invalid_ssl_mode(mode: &str) -> mlua::Error {
+    mlua::Error::FromLuaConversionError {
+        from: "string",
+        to: "ssl_mode".into(),
+        message: Some(format!(
+            "Unknown ssl mode `{mode}`. Expected one of: disable, allow, prefer, require, verify-ca, verify-full"
+        )),
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ConnectOptions(pub(crate) PgConnectOptions);
+
+impl ToTypename for ConnectOptions {
+    fn to_typename() -> Type {
+        Type::new_single("ConnectOptions", KindOfType::External)
+    }
+}
+
+impl FromLua for ConnectOptions {
+    fn from_lua(value: Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        let x = if let Value::Table(x) = value {
+            x
+        } else {
+            return Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "ConnectOptions".into(),
+                message: None,
+            });
+        };
+        let mut options = match x.get::<Option<String>>("url")? {
+            Some(url) => PgConnectOptions::from_str(&url).map_err(invalid_connection_string)?,
+            None => PgConnectOptions::new(),
+        };
+        if let Some(host) = x.get::<Option<String>>("host")? {
+            options = options.host(&host);
+        }
+        if let Some(port) = x.get::<Option<u16>>("port")? {
+            options = options.port(port);
+        }
+        if let Some(socket) = x.get::<Option<String>>("socket")? {
+            options = options.socket(socket);
+        }
+        if let Some(user) = x.get::<Option<String>>("user")? {
+            options = options.username(&user);
+        }
+        if let Some(password) = x.get::<Option<String>>("password")? {
+            options = options.password(&password);
+        }
+        if let Some(database) = x.get::<Option<String>>("database")? {
+            options = options.database(&database);
+        }
+        if let Some(application_name) = x.get::<Option<String>>("application_name")? {
+            options = options.application_name(&application_name);
+        }
+        if let Some(capacity) = x.get::<Option<usize>>("statement_cache_capacity")? {
+            options = options.statement_cache_capacity(capacity);
+        }
+        if let Some(digits) = x.get::<Option<i8>>("extra_float_digits")? {
+            options = options.extra_float_digits(digits);
+        }
+        if let Some(settings) = x.get::<Option<BTreeMap<String, String>>>("options")? {
+            options = options.options(settings);
+        }
+        if let Some(mode) = x.get::<Option<String>>("ssl_mode")? {
+            let ssl_mode = PgSslMode::from_str(&mode).map_err(|_| invalid_ssl_mode(&mode))?;
+            options = options.ssl_mode(ssl_mode);
+        }
+        if let Some(path) = x.get::<Option<String>>("ssl_root_cert")? {
+            options = options.ssl_root_cert(path);
+        }
+        if let Some(path) = x.get::<Option<String>>("ssl_client_cert")? {
+            options = options.ssl_client_cert(path);
+        }
+        if let Some(path) = x.get::<Option<String>>("ssl_client_key")? {
+            options = options.ssl_client_key(path);
+        }
+        if let Some(threshold) = x.get::<Option<f64>>("slow_query_threshold")? {
+            //sqlx logs these through the `log` crate, so they show up in whatever logger the host application uses
+            options = options.log_slow_statements(
+                log::LevelFilter::Warn,
+                seconds_to_duration("slow_query_threshold", threshold)?,
+            );
+        }
+        Ok(ConnectOptions(options))
+    }
+}
+
+impl tealr::TypeBody for ConnectOptions {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<Option<String>>("url"));
+        a.fields.push(Field::new::<Option<String>>("host"));
+        a.fields.push(Field::new::<Option<u16>>("port"));
+        a.fields.push(Field::new::<Option<String>>("socket"));
+        a.fields.push(Field::new::<Option<String>>("user"));
+        a.fields.push(Field::new::<Option<String>>("password"));
+        a.fields.push(Field::new::<Option<String>>("database"));
+        a.fields
+            .push(Field::new::<Option<String>>("application_name"));
+        a.fields
+            .push(Field::new::<Option<usize>>("statement_cache_capacity"));
+        a.fields
+            .push(Field::new::<Option<i8>>("extra_float_digits"));
+        a.fields
+            .push(Field::new::<Option<BTreeMap<String, String>>>("options"));
+        a.fields.push(Field::new::<Option<String>>("ssl_mode"));
+        a.fields.push(Field::new::<Option<String>>("ssl_root_cert"));
+        a.fields
+            .push(Field::new::<Option<String>>("ssl_client_cert"));
+        a.fields
+            .push(Field::new::<Option<String>>("ssl_client_key"));
+        a.fields
+            .push(Field::new::<Option<f64>>("slow_query_threshold"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
+
+pub(crate) struct ConnectTo(pub(crate) PgConnectOptions);
+
+impl ToTypename for ConnectTo {
+    fn to_typename() -> Type {
+        Type::Or(vec![String::to_typename(), ConnectOptions::to_typename()])
+    }
+}
+
+impl FromLua for ConnectTo {
+    fn from_lua(value: Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(x) => PgConnectOptions::from_str(&x.to_str()?)
+                .map(ConnectTo)
+                .map_err(invalid_connection_string),
+            x => ConnectOptions::from_lua(x, lua).map(|v| ConnectTo(v.0)),
+        }
+    }
+}
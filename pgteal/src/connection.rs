@@ -1,88 +1,310 @@
 use std::collections::BTreeMap;
+use std::io::Write;
 use std::iter::FromIterator;
-use std::{ops::DerefMut, sync::Arc};
+use std::{
+    future::Future,
+    ops::DerefMut,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
 
+use crate::batch::{BatchResult, BatchStatement};
 use crate::bind_params::bind_params_on;
-use crate::{internal_connection_wrapper::WrappedConnection, iter::Iter, pg_row::LuaRow};
+use crate::copy::{encode_rows, CopyFormat, CopySource, CopyTarget};
+use crate::cursor::{Cursor, OpenCursors};
+use crate::describe::Description;
+use crate::pool_options::seconds_to_duration;
+use crate::query_log::{QueryHook, QueryLog, RowCount, StreamedQuery};
+use crate::row_iter::{BorrowedConnection, RowIter};
+use crate::statement::LuaStatement;
+use crate::{
+    internal_connection_wrapper::WrappedConnection,
+    iter::Iter,
+    pg_row::{LuaRow, PositionalRow},
+};
 use either::Either;
 use futures::prelude::stream::StreamExt;
 use mlua::FromLua;
 use parking_lot::{MappedMutexGuard, Mutex};
 use shared::Input;
-use sqlx::PgConnection;
 use sqlx::{
-    pool::PoolConnection, postgres::PgArguments, query::Query, Executor, Postgres, Statement,
+    pool::PoolConnection,
+    postgres::{PgArguments, PgStatement},
+    query::Query,
+    Column, Executor, Postgres, Statement,
 };
+use sqlx::{postgres::PgConnectOptions, Connection, PgConnection};
 use tealr::mlu::mlua;
 use tealr::mlu::TealData;
 use tealr::{RecordGenerator, ToTypename};
 use tokio::runtime::Runtime;
 
-#[derive(Default)]
-pub(crate) struct QueryParamCollection(BTreeMap<usize, Input>);
+#[derive(Default, Clone)]
+pub(crate) struct QueryParamCollection {
+    positional: BTreeMap<usize, Input>,
+    named: BTreeMap<String, Input>,
+}
 impl ToTypename for QueryParamCollection {
     fn to_typename() -> tealr::Type {
-        Vec::<Input>::to_typename()
-    }
-    fn to_function_param() -> Vec<tealr::FunctionParam> {
-        Vec::<Input>::to_function_param()
+        //an array for `$n` parameters, or a map for `:name` parameters
+        tealr::Type::Map(tealr::MapRepresentation {
+            key: tealr::Type::Or(vec![String::to_typename(), i64::to_typename()]).into(),
+            value: Input::to_typename().into(),
+        })
     }
 }
 
 impl FromLua for QueryParamCollection {
     fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> std::result::Result<Self, mlua::Error> {
-        let map = if value.is_nil() {
-            BTreeMap::new()
-        } else {
-            BTreeMap::from_lua(value, lua)?
+        let mut params = Self::default();
+        let table = match value {
+            mlua::Value::Nil => return Ok(params),
+            mlua::Value::Table(x) => x,
+            x => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: x.type_name(),
+                    to: "QueryParamCollection".into(),
+                    message: Some("The parameters need to be given as a table".into()),
+                })
+            }
         };
-        Ok(Self(map))
+        for pair in table.pairs::<mlua::Value, Input>() {
+            let (key, value) = pair?;
+            match key {
+                mlua::Value::String(name) => {
+                    params.named.insert(name.to_str()?.to_string(), value);
+                }
+                key => {
+                    params.positional.insert(usize::from_lua(key, lua)?, value);
+                }
+            }
+        }
+        Ok(params)
+    }
+}
+
+impl mlua::IntoLua for QueryParamCollection {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        for (key, value) in self.positional {
+            table.raw_set(key, value)?;
+        }
+        for (key, value) in self.named {
+            table.raw_set(key, value)?;
+        }
+        Ok(mlua::Value::Table(table))
     }
 }
 
 impl FromIterator<(usize, Input)> for QueryParamCollection {
     fn from_iter<T: IntoIterator<Item = (usize, Input)>>(iter: T) -> Self {
-        Self(BTreeMap::from_iter(iter))
+        Self {
+            positional: BTreeMap::from_iter(iter),
+            named: BTreeMap::new(),
+        }
     }
 }
 
 impl QueryParamCollection {
     fn insert(&mut self, key: usize, value: Input) {
-        self.0.insert(key, value);
+        self.positional.insert(key, value);
     }
     fn append(&mut self, other: &mut QueryParamCollection) {
-        self.0.append(&mut other.0);
+        self.positional.append(&mut other.positional);
     }
     fn extend(&mut self, other: QueryParamCollection) {
-        self.0.extend(other.0);
+        self.positional.extend(other.positional);
+    }
+    fn len(&self) -> usize {
+        self.positional.len() + self.named.len()
     }
     pub fn remove(&mut self, key: &usize) -> Option<Input> {
-        self.0.remove(key)
+        self.positional.remove(key)
+    }
+    ///A copy of these parameters where every value is replaced, so they can be logged safely.
+    pub(crate) fn redacted(&self) -> Self {
+        let redacted = || Input::String("<redacted>".into());
+        Self {
+            positional: self.positional.keys().map(|v| (*v, redacted())).collect(),
+            named: self.named.keys().map(|v| (v.clone(), redacted())).collect(),
+        }
+    }
+    ///Turns the named parameters into positional ones, where the name at index 0 becomes `$1`
+    pub(crate) fn resolve_known_names(&mut self, names: &[String]) -> Result<(), mlua::Error> {
+        if self.named.is_empty() && names.is_empty() {
+            return Ok(());
+        }
+        if !self.positional.is_empty() {
+            let message = if self.named.is_empty() {
+                "The query uses `:name` parameters, so their values need to be given by name."
+            } else {
+                "Parameters need to be given either by position or by name, not both."
+            };
+            return Err(crate::base::Error::Custom(message.into()).into());
+        }
+        let mut missing = Vec::new();
+        for (index, name) in names.iter().enumerate() {
+            match self.named.remove(name) {
+                Some(value) => {
+                    self.positional.insert(index + 1, value);
+                }
+                None => missing.push(name.as_str()),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(crate::base::Error::Custom(format!(
+                "Missing values for the parameters: `:{}`",
+                missing.join("`, `:")
+            ))
+            .into());
+        }
+        if !self.named.is_empty() {
+            let unused = self.named.keys().cloned().collect::<Vec<_>>();
+            return Err(crate::base::Error::Custom(format!(
+                "Got values for parameters that the query does not use: `{}`",
+                unused.join("`, `")
+            ))
+            .into());
+        }
+        Ok(())
+    }
+    ///Rewrites the `:name` parameters in the sql to `$n` and turns the named parameters into positional ones that match.
+    ///Queries without `:name` parameters keep their positional parameters as they are.
+    fn resolve_names(&mut self, sql: String) -> Result<String, mlua::Error> {
+        let (sql, names) = crate::named_params::rewrite_named_params(&sql);
+        self.resolve_known_names(&names)?;
+        Ok(sql)
+    }
+}
+
+//how long to wait for a query that timed out but could not be cancelled, before giving up on its connection
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+///If the error is the one postgres gives for a query that got cancelled (SQLSTATE 57014)
+fn is_query_canceled(error: &mlua::Error) -> bool {
+    let error = match error {
+        mlua::Error::ExternalError(x) => x,
+        _ => return false,
+    };
+    let error = match error.downcast_ref::<crate::base::Error>() {
+        Some(crate::base::Error::Sqlx(x)) => x,
+        Some(crate::base::Error::BatchStatement(_, x)) => return is_query_canceled(x),
+        Some(_) => return false,
+        None => match error.downcast_ref::<sqlx::Error>() {
+            Some(x) => x,
+            None => return false,
+        },
+    };
+    matches!(error, sqlx::Error::Database(x) if x.code().as_deref() == Some("57014"))
+}
+
+///The parts of a connection needed to cancel its queries. Unlike `LuaConnection`, it can be send to other threads.
+#[derive(Clone)]
+pub(crate) struct Canceller {
+    connection: Arc<Mutex<Option<WrappedConnection>>>,
+    connect_options: Arc<PgConnectOptions>,
+    pid: i32,
+    timeout: Duration,
+}
+
+impl Canceller {
+    ///Returns if postgres managed to send the cancel signal to the backend.
+    async fn cancel_backend(&self) -> Result<bool, sqlx::Error> {
+        let mut con = PgConnection::connect_with(&self.connect_options).await?;
+        let cancelled = sqlx::query_scalar("SELECT pg_cancel_backend($1)")
+            .bind(self.pid)
+            .fetch_one(&mut con)
+            .await?;
+        con.close().await?;
+        Ok(cancelled)
+    }
+    ///Throws away a connection that is stuck on a query, instead of giving it back to its pool.
+    fn discard_connection(&self) {
+        let con = self.connection.lock().take();
+        if let Some(WrappedConnection::PoolConnection(x)) = con {
+            //a detached connection gets replaced by the pool, instead of being reused
+            drop(x.detach());
+        }
+    }
+    ///Runs the given query future. If it takes longer than the timeout, the query gets cancelled on the server.
+    ///
+    ///The query can still finish between the timeout and the cancel reaching the server.
+    ///In that case its result is returned as normal, so a write that went through is never reported as a timeout.
+    pub(crate) async fn with_timeout<T>(
+        &self,
+        query: impl Future<Output = Result<T, mlua::Error>>,
+    ) -> Result<T, mlua::Error> {
+        let timeout = self.timeout;
+        let mut query = Box::pin(query);
+        if let Ok(x) = tokio::time::timeout(timeout, &mut query).await {
+            return x;
+        }
+        let cancelled = self.cancel_backend().await;
+        let res = match cancelled {
+            //the query ends soon after being cancelled. Wait for it, so the connection can be used again
+            Ok(true) => query.await,
+            //the query may still end on its own, but without a cancel there is no telling when
+            _ => match tokio::time::timeout(CANCEL_GRACE_PERIOD, &mut query).await {
+                Ok(x) => x,
+                Err(_) => {
+                    drop(query);
+                    self.discard_connection();
+                    return Err(match cancelled {
+                        Err(x) => crate::base::Error::DBErrorAfterHandling(
+                            x,
+                            crate::base::Error::Timeout(timeout).into(),
+                        ),
+                        Ok(_) => crate::base::Error::Custom(format!(
+                            "The query took longer than {} seconds and could not be cancelled. Its connection got closed instead.",
+                            timeout.as_secs_f64()
+                        )),
+                    }
+                    .into());
+                }
+            },
+        };
+        match res {
+            Err(x) if is_query_canceled(&x) => Err(crate::base::Error::Timeout(timeout).into()),
+            x => x,
+        }
     }
 }
 
-fn get_lock(
+pub(crate) fn get_lock(
     con: &'_ Arc<Mutex<Option<WrappedConnection>>>,
 ) -> Result<MappedMutexGuard<'_, WrappedConnection>, mlua::Error> {
     let x = con.lock();
-    parking_lot::lock_api::MutexGuard::<'_, _, _>::try_map(x, |v| v.as_mut()).map_err(|_| {
-        mlua::Error::external(crate::base::Error::Custom(
-            "Connection already dropped".into(),
-        ))
-    })
+    parking_lot::lock_api::MutexGuard::<'_, _, _>::try_map(x, |v| v.as_mut())
+        .map_err(|_| missing_connection(con))
+}
+
+///The error for when the connection is not in its slot.
+pub(crate) fn missing_connection(slot: &Mutex<Option<WrappedConnection>>) -> mlua::Error {
+    let message = if crate::row_iter::is_lent_out(slot) {
+        "Connection is busy with an open row iterator. Read all of its rows or close it first"
+    } else {
+        "Connection already dropped"
+    };
+    mlua::Error::external(crate::base::Error::Custom(message.into()))
 }
 
-async fn add_params<'b, 'a: 'b>(
+async fn prepare_params<'b, 'a: 'b>(
     connection: &'a Arc<Mutex<Option<WrappedConnection>>>,
-    sql: &'a str,
+    sql: &'a mut String,
     params: &'b mut QueryParamCollection,
 ) -> Result<
     (
         Query<'b, Postgres, PgArguments>,
+        PgStatement<'a>,
         MappedMutexGuard<'a, WrappedConnection>,
     ),
     mlua::Error,
 > {
+    *sql = params.resolve_names(std::mem::take(sql))?;
+    let sql: &'a str = sql;
     let mut v = get_lock(connection)?;
     let statement = v.prepare(sql).await.map_err(mlua::Error::external)?;
     let query = sqlx::query(sql);
@@ -91,15 +313,114 @@ async fn add_params<'b, 'a: 'b>(
         statement.parameters().unwrap_or(Either::Right(0)),
         query,
     )?;
+    Ok((query, statement, v))
+}
+
+pub(crate) async fn add_params<'b, 'a: 'b>(
+    connection: &'a Arc<Mutex<Option<WrappedConnection>>>,
+    sql: &'a mut String,
+    params: &'b mut QueryParamCollection,
+) -> Result<
+    (
+        Query<'b, Postgres, PgArguments>,
+        MappedMutexGuard<'a, WrappedConnection>,
+    ),
+    mlua::Error,
+> {
+    let (query, _, v) = prepare_params(connection, sql, params).await?;
     Ok((query, v))
 }
 
+//The bodies of the queries, once their parameters are bound. Shared by `Connection` and `Statement`
+
+pub(crate) async fn execute_bound(
+    query: Query<'_, Postgres, PgArguments>,
+    con: &mut WrappedConnection,
+) -> mlua::Result<u64> {
+    let x = query.execute(con).await.map_err(mlua::Error::external)?;
+    Ok(x.rows_affected())
+}
+
+pub(crate) async fn fetch_optional_bound(
+    query: Query<'_, Postgres, PgArguments>,
+    con: &mut WrappedConnection,
+    keep_nulls: bool,
+) -> mlua::Result<Option<LuaRow>> {
+    let x = query
+        .fetch_optional(con)
+        .await
+        .map_err(mlua::Error::external)?;
+    Ok(x.map(|v| LuaRow::new(v, keep_nulls)))
+}
+
+pub(crate) async fn fetch_one_bound(
+    query: Query<'_, Postgres, PgArguments>,
+    con: &mut WrappedConnection,
+    keep_nulls: bool,
+) -> mlua::Result<LuaRow> {
+    let x = query.fetch_one(con).await.map_err(mlua::Error::external)?;
+    Ok(LuaRow::new(x, keep_nulls))
+}
+
+pub(crate) async fn fetch_all_bound(
+    query: Query<'_, Postgres, PgArguments>,
+    con: &mut WrappedConnection,
+    keep_nulls: bool,
+) -> mlua::Result<Vec<LuaRow>> {
+    let mut stream = query.fetch(con);
+    let mut items = Vec::new();
+    loop {
+        let next = stream.next().await;
+        match next {
+            Some(Ok(x)) => items.push(LuaRow::new(x, keep_nulls)),
+            Some(Err(x)) => return Err(mlua::Error::external(x)),
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+fn column_names(statement: &PgStatement<'_>) -> Vec<String> {
+    statement
+        .columns()
+        .iter()
+        .map(|v| v.name().to_string())
+        .collect()
+}
+
+///The function given to `begin`. Its first return value decides if the transaction gets committed
+pub(crate) type TransactionFunction = tealr::mlu::TypedFunction<
+    LuaConnection<'static>,
+    (Option<bool>, mlua::Variadic<Option<crate::Res>>),
+>;
+
 #[derive(Clone)]
 pub(crate) struct LuaConnection<'c> {
     runtime: Arc<Runtime>,
     connection: Option<Arc<Mutex<Option<WrappedConnection>>>>,
+    //used to open a second connection that cancels queries which take too long
+    connect_options: Arc<PgConnectOptions>,
+    backend_pid: Arc<Mutex<Option<i32>>>,
+    //set for the connection given to the function of `begin`
+    in_transaction: bool,
+    on_query: Arc<Mutex<Option<QueryHook>>>,
+    //the iterator made by `rows` that currently has the connection
+    row_iter: Arc<Mutex<Weak<Mutex<BorrowedConnection>>>>,
+    cursors: Arc<Mutex<OpenCursors>>,
+    //set by `set_keep_nulls`, shared with the handles that cursors, statements and `begin` get
+    keep_nulls: Arc<AtomicBool>,
     _x: std::marker::PhantomData<&'c ()>,
 }
+impl Drop for LuaConnection<'_> {
+    fn drop(&mut self) {
+        //giving a connection back to its pool spawns a task, which needs to happen inside the runtime.
+        //Without this, a connection that gets garbage collected would cause a panic instead.
+        if let Some(Ok(connection)) = self.connection.take().map(Arc::try_unwrap) {
+            let _guard = self.runtime.enter();
+            drop(connection);
+        }
+    }
+}
 impl ToTypename for LuaConnection<'_> {
     fn to_typename() -> tealr::Type {
         tealr::Type::new_single("Connection", tealr::KindOfType::External)
@@ -171,6 +492,9 @@ fn sanitize_db_table_name(name: String, should_get_quoted: bool) -> Result<Strin
 
 impl<'c> LuaConnection<'c> {
     pub(crate) fn drop_con(&self) -> Result<(), mlua::Error> {
+        self.end_row_iter();
+        //the connection gets released even if the cursors could not be closed
+        let cursors_closed = self.close_cursors();
         self.runtime.block_on(async {
             let mut x = self
                 .connection
@@ -181,11 +505,59 @@ impl<'c> LuaConnection<'c> {
                     ))
                 })?
                 .lock();
-            *x = None;
-            Ok(())
+            let con = x.take();
+            if let (Err(_), Some(WrappedConnection::PoolConnection(con))) = (&cursors_closed, con) {
+                //the transaction of the cursors might still be open, so the connection can't be reused
+                drop(con.detach());
+            }
+            cursors_closed
         })
     }
-    fn unwrap_connection_option(
+    ///Closes the cursors that are still open, rolling back the transaction they started.
+    fn close_cursors(&self) -> Result<(), mlua::Error> {
+        let sql = self.cursors.lock().take_release_sql();
+        match sql {
+            Some(sql) => self
+                .run_query(sql, Default::default(), None, |sql, _| {
+                    self.execute_script(sql)
+                })
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+    pub(crate) fn open_cursors(&self) -> &Arc<Mutex<OpenCursors>> {
+        &self.cursors
+    }
+    ///Makes the iterator made by `rows` give back the connection, if it still has it.
+    fn end_row_iter(&self) {
+        let iter = self.row_iter.lock().upgrade();
+        if let Some(iter) = iter {
+            let _guard = self.runtime.enter();
+            let log = {
+                let mut iter = iter.lock();
+                iter.finish();
+                iter.take_log()
+            };
+            //the hook runs without holding the lock, so it can still use the iterator
+            if let Some(log) = log {
+                log.report(None);
+            }
+        }
+    }
+    ///Closes the underlying connection instead of giving it back to the pool.
+    pub(crate) fn close_con(&self) -> Result<(), mlua::Error> {
+        self.end_row_iter();
+        //closing the connection ends the transaction of the cursors as well
+        self.cursors.lock().take_release_sql();
+        let con = self.unwrap_connection_option()?.lock().take();
+        if let Some(WrappedConnection::PoolConnection(x)) = con {
+            self.runtime
+                .block_on(x.close())
+                .map_err(crate::base::Error::from)?;
+        }
+        Ok(())
+    }
+    pub(crate) fn unwrap_connection_option(
         &self,
     ) -> Result<&Arc<Mutex<Option<WrappedConnection>>>, mlua::Error> {
         self.connection.as_ref().ok_or_else(|| {
@@ -197,7 +569,7 @@ impl<'c> LuaConnection<'c> {
 
     async fn add_params<'b, 'a: 'b>(
         &'a self,
-        sql: &'a str,
+        sql: &'a mut String,
         params: &'b mut QueryParamCollection,
     ) -> Result<
         (
@@ -208,13 +580,249 @@ impl<'c> LuaConnection<'c> {
     > {
         add_params(self.unwrap_connection_option()?, sql, params).await
     }
-    async fn execute(&self, query: String, mut params: QueryParamCollection) -> mlua::Result<u64> {
-        let (query, mut v) = self.add_params(&query, &mut params).await?;
+    pub(crate) async fn execute(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<u64> {
+        let (query, mut v) = self.add_params(&mut query, &mut params).await?;
+        execute_bound(query, v.deref_mut()).await
+    }
+    pub(crate) async fn fetch_optional(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<Option<LuaRow>> {
+        let (query, mut v) = self.add_params(&mut query, &mut params).await?;
+        fetch_optional_bound(query, v.deref_mut(), self.keep_nulls()).await
+    }
+    pub(crate) async fn fetch_one(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<LuaRow> {
+        let (query, mut v) = self.add_params(&mut query, &mut params).await?;
+        fetch_one_bound(query, v.deref_mut(), self.keep_nulls()).await
+    }
+    pub(crate) async fn fetch_all(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<Vec<LuaRow>> {
+        let (query, mut v) = self.add_params(&mut query, &mut params).await?;
+        fetch_all_bound(query, v.deref_mut(), self.keep_nulls()).await
+    }
+    pub(crate) async fn execute_returning(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<(Vec<LuaRow>, u64)> {
+        let (query, mut v) = self.add_params(&mut query, &mut params).await?;
+        let mut stream = v.deref_mut().fetch_many(query);
+        let mut rows = Vec::new();
+        let mut affected = 0;
+        while let Some(x) = stream.next().await {
+            match x {
+                Ok(Either::Left(x)) => affected += x.rows_affected(),
+                Ok(Either::Right(x)) => rows.push(LuaRow::new(x, self.keep_nulls())),
+                Err(x) => return Err(mlua::Error::external(x)),
+            }
+        }
+        Ok((rows, affected))
+    }
+    pub(crate) async fn fetch_optional_positional(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<(Option<PositionalRow>, Vec<String>)> {
+        let (query, statement, mut v) =
+            prepare_params(self.unwrap_connection_option()?, &mut query, &mut params).await?;
+        let x = query
+            .fetch_optional(v.deref_mut())
+            .await
+            .map_err(mlua::Error::external)?;
+        Ok((
+            x.map(|v| PositionalRow::new(v, self.keep_nulls())),
+            column_names(&statement),
+        ))
+    }
+    pub(crate) async fn fetch_one_positional(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<(PositionalRow, Vec<String>)> {
+        let (query, statement, mut v) =
+            prepare_params(self.unwrap_connection_option()?, &mut query, &mut params).await?;
         let x = query
-            .execute(v.deref_mut())
+            .fetch_one(v.deref_mut())
             .await
             .map_err(mlua::Error::external)?;
-        Ok(x.rows_affected())
+        Ok((
+            PositionalRow::new(x, self.keep_nulls()),
+            column_names(&statement),
+        ))
+    }
+    pub(crate) async fn fetch_all_positional(
+        &self,
+        mut query: String,
+        mut params: QueryParamCollection,
+    ) -> mlua::Result<(Vec<PositionalRow>, Vec<String>)> {
+        let (query, statement, mut v) =
+            prepare_params(self.unwrap_connection_option()?, &mut query, &mut params).await?;
+        let x = query
+            .fetch_all(v.deref_mut())
+            .await
+            .map_err(mlua::Error::external)?;
+        Ok((
+            x.into_iter()
+                .map(|v| PositionalRow::new(v, self.keep_nulls()))
+                .collect(),
+            column_names(&statement),
+        ))
+    }
+    ///Runs the statements one after another, stopping at the first one that fails.
+    ///sqlx has no way to pipeline statements with parameters, so those wait for the previous one to finish.
+    ///Statements without parameters that follow each other get sent together, see `batch_group`.
+    ///Every statement that ran gets added to `logs` if there is an `on_query` hook, as the hook can only run once the batch is done.
+    pub(crate) async fn batch(
+        &self,
+        statements: Vec<BatchStatement>,
+        logs: &mut Vec<QueryLog>,
+    ) -> mlua::Result<Vec<BatchResult>> {
+        let connection = self.unwrap_connection_option()?;
+        let hook = self.on_query.lock().clone();
+        let mut results = Vec::with_capacity(statements.len());
+        let mut statements = statements.into_iter().enumerate().peekable();
+        while let Some((index, statement)) = statements.next() {
+            let grouped_with_next = statements
+                .peek()
+                .is_some_and(|(_, next)| next.can_be_grouped());
+            if statement.can_be_grouped() && grouped_with_next {
+                let mut group = vec![statement.sql];
+                while let Some((_, next)) = statements.next_if(|(_, v)| v.can_be_grouped()) {
+                    group.push(next.sql);
+                }
+                self.batch_group(connection, index, group, hook.as_ref(), logs, &mut results)
+                    .await?;
+                continue;
+            }
+            let BatchStatement { mut sql, params } = statement;
+            let mut params = params.unwrap_or_default();
+            let pending = hook.as_ref().map(|v| v.start(&sql, &params));
+            let started = Instant::now();
+            let res = async {
+                let (query, statement, mut v) =
+                    prepare_params(connection, &mut sql, &mut params).await?;
+                let returns_rows = !statement.columns().is_empty();
+                let mut stream = v.deref_mut().fetch_many(query);
+                let mut rows = Vec::new();
+                let mut affected = 0;
+                while let Some(x) = stream.next().await {
+                    match x {
+                        Ok(Either::Left(x)) => affected += x.rows_affected(),
+                        Ok(Either::Right(x)) => rows.push(LuaRow::new(x, self.keep_nulls())),
+                        Err(x) => return Err(mlua::Error::external(x)),
+                    }
+                }
+                Ok(if returns_rows {
+                    BatchResult::Rows(rows)
+                } else {
+                    BatchResult::Affected(affected)
+                })
+            }
+            .await;
+            if let Some(pending) = pending {
+                logs.push(pending.finish(started.elapsed(), &res));
+            }
+            match res {
+                Ok(x) => results.push(x),
+                Err(x) => return Err(crate::base::Error::BatchStatement(index, x).into()),
+            }
+        }
+        Ok(results)
+    }
+    ///Sends the statements as a single simple query, so they only take one round trip.
+    ///The results get split up again using the end of every statement that the database reports.
+    ///As the database doesn't say if a statement that returned no rows could have returned rows,
+    ///those always end up as the amount of affected rows.
+    async fn batch_group(
+        &self,
+        connection: &Arc<Mutex<Option<WrappedConnection>>>,
+        first_index: usize,
+        statements: Vec<String>,
+        hook: Option<&QueryHook>,
+        logs: &mut Vec<QueryLog>,
+        results: &mut Vec<BatchResult>,
+    ) -> mlua::Result<()> {
+        //the newline keeps a `--` comment at the end of a statement from swallowing the `;`
+        let sql = statements.join("\n;\n");
+        let mut con = get_lock(connection)?;
+        let mut stream = con.deref_mut().fetch_many(sql.as_str());
+        let mut statements = statements.into_iter();
+        let mut index = first_index;
+        let mut rows = Vec::new();
+        let mut started = Instant::now();
+        loop {
+            let res = match stream.next().await {
+                Some(Ok(Either::Right(x))) => {
+                    rows.push(LuaRow::new(x, self.keep_nulls()));
+                    continue;
+                }
+                Some(Ok(Either::Left(x))) => Ok(if rows.is_empty() {
+                    BatchResult::Affected(x.rows_affected())
+                } else {
+                    BatchResult::Rows(std::mem::take(&mut rows))
+                }),
+                Some(Err(x)) => Err(mlua::Error::external(x)),
+                None => break,
+            };
+            let sql = statements.next().ok_or_else(|| {
+                crate::base::Error::BatchStatement(
+                    index - 1,
+                    crate::base::Error::Custom(
+                        "Every item of a batch needs to contain exactly one statement".into(),
+                    )
+                    .into(),
+                )
+            })?;
+            if let Some(hook) = hook {
+                logs.push(
+                    hook.start(&sql, &Default::default())
+                        .finish(started.elapsed(), &res),
+                );
+            }
+            started = Instant::now();
+            match res {
+                Ok(x) => results.push(x),
+                Err(x) => return Err(crate::base::Error::BatchStatement(index, x).into()),
+            }
+            index += 1;
+        }
+        if statements.next().is_some() {
+            return Err(crate::base::Error::BatchStatement(
+                index,
+                crate::base::Error::Custom(
+                    "Every item of a batch needs to contain exactly one statement".into(),
+                )
+                .into(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+    pub(crate) async fn execute_script(&self, sql: String) -> mlua::Result<Vec<u64>> {
+        let mut con = get_lock(self.unwrap_connection_option()?)?;
+        //a query without arguments goes over the simple query protocol, which allows multiple statements
+        let mut stream = con.deref_mut().fetch_many(sql.as_str());
+        let mut affected = Vec::new();
+        while let Some(x) = stream.next().await {
+            match x {
+                Ok(Either::Left(x)) => affected.push(x.rows_affected()),
+                Ok(Either::Right(_)) => (),
+                Err(x) => return Err(mlua::Error::external(x)),
+            }
+        }
+        Ok(affected)
     }
     fn extract_lua_to_table_fields(
         values: BTreeMap<String, Input>,
@@ -234,32 +842,400 @@ impl<'c> LuaConnection<'c> {
             .collect::<QueryParamCollection>();
         Ok((keys, markers, values))
     }
-    pub(crate) fn new(connection: PgConnection, runtime: Arc<Runtime>) -> Self {
+    ///Builds the `where` part of the `update` and `delete` shorthands, with the parameters it needs.
+    ///NULL never equals anything, so columns that need to be NULL get `IS NULL` instead of a parameter.
+    fn where_clause(
+        values: BTreeMap<String, Input>,
+        continue_from: usize,
+    ) -> Result<(String, QueryParamCollection), mlua::Error> {
+        let (nulls, values): (BTreeMap<_, _>, BTreeMap<_, _>) = values
+            .into_iter()
+            .partition(|(_, value)| matches!(value, Input::Null));
+        let (keys, markers, values) = Self::extract_lua_to_table_fields(values, continue_from)?;
+        let mut parts = keys
+            .into_iter()
+            .zip(markers)
+            .map(|(key, marker)| format!("{} = ${}", key, marker))
+            .collect::<Vec<_>>();
+        for key in nulls.into_keys() {
+            parts.push(format!("{} IS NULL", sanitize_db_table_name(key, true)?));
+        }
+        Ok((parts.join("\n AND "), values))
+    }
+    ///Runs the given function inside a transaction.
+    ///It gets committed or rolled back depending on the result of the function
+    pub(crate) fn begin(
+        &mut self,
+        func: TransactionFunction,
+    ) -> Result<(bool, mlua::Variadic<Option<crate::Res>>), mlua::Error> {
+        let connection = self.connection.take().ok_or_else(|| {
+            mlua::Error::external(crate::base::Error::Custom(
+                "Tried to use a connection that is used for a transaction.".into(),
+            ))
+        })?;
+        let res = self.transaction_statement(&connection, "BEGIN")?;
+        if let Err(x) = res {
+            self.connection = Some(connection);
+            return Err(mlua::Error::external(crate::base::Error::Sqlx(x)));
+        }
+        let mut lua_con = LuaConnection::from_wrapped(
+            connection.clone(),
+            self.runtime.clone(),
+            self.connect_options.clone(),
+        );
+        lua_con.on_query = self.on_query.clone();
+        lua_con.row_iter = self.row_iter.clone();
+        lua_con.keep_nulls = self.keep_nulls.clone();
+        let res = func.call(lua_con.clone()).map(|v| match v {
+            (None, x) => (true, x),
+            (Some(x), y) => (x, y),
+        });
+        //an iterator that is still reading rows would keep the connection from committing
+        self.end_row_iter();
+        //cursors declared inside the transaction end together with it
+        lua_con.cursors.lock().take_release_sql();
+
+        let action = match &res {
+            Ok((true, _)) => "COMMIT",
+            Ok((false, _)) => "ROLLBACK",
+            Err(_) => "ROLLBACK",
+        };
+        let rollback_res = self.transaction_statement(&connection, action)?;
+        self.connection = Some(connection);
+        match (res, rollback_res) {
+            (Err(res_error), Err(rollback_error)) => Err(mlua::Error::external(
+                crate::base::Error::DBErrorAfterHandling(rollback_error, res_error),
+            )),
+            (Err(res_err), _) => Err(res_err),
+            (_, Err(x)) => Err(mlua::Error::external(crate::base::Error::Sqlx(x))),
+            (Ok(x), Ok(_)) => Ok(x),
+        }
+    }
+    ///Runs one of the statements that `begin` uses to start or end its transaction and passes it to the `on_query` hook.
+    ///The lock on the connection is released before the hook runs, as the hook may use the connection of the transaction.
+    fn transaction_statement(
+        &self,
+        connection: &Mutex<Option<WrappedConnection>>,
+        sql: &str,
+    ) -> Result<Result<(), sqlx::Error>, mlua::Error> {
+        let hook = self.on_query.lock().clone();
+        let pending = hook.as_ref().map(|v| v.start(sql, &Default::default()));
+        let started = Instant::now();
+        let res = {
+            let mut guard = connection.lock();
+            let con = guard
+                .as_mut()
+                .ok_or_else(|| missing_connection(connection))?;
+            self.runtime.block_on(con.execute(sql)).map(|_| ())
+        };
+        if let (Some(hook), Some(pending)) = (hook, pending) {
+            hook.report(pending.finish(started.elapsed(), &res));
+        }
+        Ok(res)
+    }
+    pub(crate) fn new(
+        connection: PgConnection,
+        runtime: Arc<Runtime>,
+        connect_options: Arc<PgConnectOptions>,
+    ) -> Self {
         LuaConnection {
             connection: Some(Arc::new(Mutex::new(Some(WrappedConnection::Connection(
                 connection,
             ))))),
             _x: std::marker::PhantomData,
             runtime,
+            connect_options,
+            backend_pid: Default::default(),
+            in_transaction: false,
+            on_query: Default::default(),
+            row_iter: Default::default(),
+            cursors: Default::default(),
+            keep_nulls: Default::default(),
         }
     }
     pub(crate) fn from_wrapped(
         from: Arc<Mutex<Option<WrappedConnection>>>,
         runtime: Arc<Runtime>,
+        connect_options: Arc<PgConnectOptions>,
     ) -> Self {
         LuaConnection {
             connection: Some(from),
             _x: std::marker::PhantomData,
             runtime,
+            connect_options,
+            backend_pid: Default::default(),
+            in_transaction: true,
+            on_query: Default::default(),
+            row_iter: Default::default(),
+            cursors: Default::default(),
+            keep_nulls: Default::default(),
         }
     }
-    pub(crate) fn from_pool(from: PoolConnection<Postgres>, runtime: Arc<Runtime>) -> Self {
+    pub(crate) fn from_pool(
+        from: PoolConnection<Postgres>,
+        runtime: Arc<Runtime>,
+        connect_options: Arc<PgConnectOptions>,
+    ) -> Self {
         LuaConnection {
             connection: Some(Arc::new(Mutex::new(Some(
                 WrappedConnection::PoolConnection(from),
             )))),
             _x: std::marker::PhantomData,
             runtime,
+            connect_options,
+            backend_pid: Default::default(),
+            in_transaction: false,
+            on_query: Default::default(),
+            row_iter: Default::default(),
+            cursors: Default::default(),
+            keep_nulls: Default::default(),
+        }
+    }
+    ///Sends the data of the source to a `COPY ... FROM STDIN` statement and returns the amount of copied rows.
+    ///If sending the data fails, the copy gets aborted so nothing gets inserted.
+    pub(crate) fn copy_in(
+        &self,
+        sql: String,
+        source: CopySource,
+        format: Option<CopyFormat>,
+    ) -> mlua::Result<u64> {
+        self.with_connection_taken(|con| self.copy_in_with(con, sql, source, format))
+    }
+    fn copy_in_with(
+        &self,
+        con: &mut WrappedConnection,
+        sql: String,
+        source: CopySource,
+        format: Option<CopyFormat>,
+    ) -> mlua::Result<u64> {
+        let mut copy = self
+            .runtime
+            .block_on(con.pg_connection().copy_in_raw(&sql))
+            .map_err(mlua::Error::external)?;
+        let sent = match source {
+            CopySource::Raw(x) => self.runtime.block_on(copy.send(x)).map(|_| ()),
+            CopySource::Rows(x) => {
+                let data = encode_rows(&x, format.unwrap_or(CopyFormat::Text));
+                self.runtime.block_on(copy.send(data)).map(|_| ())
+            }
+            //the function gets called outside of the runtime, as lua code can not run inside of it
+            CopySource::Chunks(func) => loop {
+                match func.call(()) {
+                    Ok(Some(chunk)) => {
+                        let chunk = chunk.as_bytes().to_vec();
+                        if let Err(x) = self.runtime.block_on(copy.send(chunk)) {
+                            break Err(x);
+                        }
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(x) => {
+                        let aborted = self.runtime.block_on(copy.abort(x.to_string()));
+                        return match aborted {
+                            Ok(()) => Err(x),
+                            Err(abort_error) => {
+                                Err(crate::base::Error::DBErrorAfterHandling(abort_error, x).into())
+                            }
+                        };
+                    }
+                }
+            },
+        };
+        match sent {
+            Ok(()) => self
+                .runtime
+                .block_on(copy.finish())
+                .map_err(mlua::Error::external),
+            //a failed send means the connection itself has problems, so aborting won't work either
+            Err(x) => Err(mlua::Error::external(x)),
+        }
+    }
+    ///Runs a `COPY ... TO STDOUT` statement and hands every chunk of data to the target.
+    pub(crate) fn copy_out(
+        &self,
+        lua: &mlua::Lua,
+        sql: String,
+        target: CopyTarget,
+    ) -> mlua::Result<()> {
+        self.with_connection_taken(|con| {
+            let mut stream = self
+                .runtime
+                .block_on(con.pg_connection().copy_out_raw(&sql))
+                .map_err(mlua::Error::external)?;
+            //only created once the query got accepted, so a failing query leaves an existing file alone
+            let mut file = match &target {
+                CopyTarget::Path(path) => match std::fs::File::create(path) {
+                    Ok(x) => Some(x),
+                    Err(x) => {
+                        self.runtime
+                            .block_on(async { while stream.next().await.is_some() {} });
+                        return Err(mlua::Error::external(x));
+                    }
+                },
+                CopyTarget::Callback(_) => None,
+            };
+            //the callback gets called outside of the runtime, as lua code can not run inside of it
+            while let Some(chunk) = self.runtime.block_on(stream.next()) {
+                let chunk = chunk.map_err(mlua::Error::external)?;
+                let written = match (&target, file.as_mut()) {
+                    (_, Some(file)) => file.write_all(&chunk).map_err(mlua::Error::external),
+                    (CopyTarget::Callback(func), None) => {
+                        lua.create_string(&chunk).and_then(|chunk| func.call(chunk))
+                    }
+                    (CopyTarget::Path(_), None) => Ok(()),
+                };
+                if let Err(x) = written {
+                    //the rest of the data still needs to be read before the connection can be used again
+                    self.runtime
+                        .block_on(async { while stream.next().await.is_some() {} });
+                    return Err(x);
+                }
+            }
+            Ok(())
+        })
+    }
+    ///Moves the connection out of its slot while `func` runs, and puts it back afterwards.
+    ///This is for functions that call lua code while using the connection.
+    ///If that lua code tries to use the connection as well, it gets an error instead of waiting on a lock that never gets released.
+    fn with_connection_taken<T>(
+        &self,
+        func: impl FnOnce(&mut WrappedConnection) -> mlua::Result<T>,
+    ) -> mlua::Result<T> {
+        let slot = self.unwrap_connection_option()?;
+        let mut con = slot.lock().take().ok_or_else(|| missing_connection(slot))?;
+        let res = func(&mut con);
+        *slot.lock() = Some(con);
+        res
+    }
+    pub(crate) fn is_in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+    ///Declares a cursor for the given query, which keeps its own handle to this connection.
+    pub(crate) fn cursor(
+        &self,
+        sql: String,
+        params: QueryParamCollection,
+    ) -> Result<Cursor, mlua::Error> {
+        self.unwrap_connection_option()?;
+        Cursor::open(self.to_static(), sql, params)
+    }
+    pub(crate) fn runtime(&self) -> Arc<Runtime> {
+        self.runtime.clone()
+    }
+    ///A handle to the same connection that can be stored in other userdata, like cursors and statements.
+    pub(crate) fn to_static(&self) -> LuaConnection<'static> {
+        LuaConnection {
+            runtime: self.runtime.clone(),
+            connection: self.connection.clone(),
+            connect_options: self.connect_options.clone(),
+            backend_pid: self.backend_pid.clone(),
+            in_transaction: self.in_transaction,
+            on_query: self.on_query.clone(),
+            row_iter: self.row_iter.clone(),
+            cursors: self.cursors.clone(),
+            keep_nulls: self.keep_nulls.clone(),
+            _x: std::marker::PhantomData,
+        }
+    }
+    pub(crate) fn set_keep_nulls(&self, keep: bool) {
+        self.keep_nulls.store(keep, Ordering::Relaxed);
+    }
+    pub(crate) fn keep_nulls(&self) -> bool {
+        self.keep_nulls.load(Ordering::Relaxed)
+    }
+    pub(crate) fn set_backend_pid(&self, pid: i32) {
+        *self.backend_pid.lock() = Some(pid);
+    }
+    pub(crate) fn set_query_hook(&self, hook: Option<QueryHook>) {
+        *self.on_query.lock() = hook;
+    }
+    ///Runs the statement and passes what happened to the `on_query` hook, if there is one.
+    pub(crate) fn log_query<T: RowCount>(
+        &self,
+        sql: String,
+        params: QueryParamCollection,
+        run: impl FnOnce(String, QueryParamCollection) -> mlua::Result<T>,
+    ) -> mlua::Result<T> {
+        let hook = self.on_query.lock().clone();
+        let hook = match hook {
+            Some(x) => x,
+            None => return run(sql, params),
+        };
+        let pending = hook.start(&sql, &params);
+        let started = Instant::now();
+        let res = run(sql, params);
+        hook.report(pending.finish(started.elapsed(), &res));
+        res
+    }
+    pub(crate) fn report_queries(&self, logs: Vec<QueryLog>) {
+        let hook = self.on_query.lock().clone();
+        if let Some(hook) = hook {
+            for log in logs {
+                hook.report(log);
+            }
+        }
+    }
+    ///Starts logging a query whose rows get read one by one, if there is an `on_query` hook.
+    pub(crate) fn start_streamed(
+        &self,
+        sql: &str,
+        params: &QueryParamCollection,
+    ) -> Option<StreamedQuery> {
+        self.on_query
+            .lock()
+            .as_ref()
+            .map(|v| v.start_streamed(sql, params))
+    }
+    ///Combines `log_query` with `block_on_with_timeout`
+    pub(crate) fn run_query<T: RowCount, F: Future<Output = mlua::Result<T>>>(
+        &self,
+        sql: String,
+        params: QueryParamCollection,
+        timeout: Option<f64>,
+        query: impl FnOnce(String, QueryParamCollection) -> F,
+    ) -> mlua::Result<T> {
+        self.log_query(sql, params, |sql, params| {
+            self.block_on_with_timeout(timeout, query(sql, params))
+        })
+    }
+    fn get_backend_pid(&self) -> Result<i32, mlua::Error> {
+        if let Some(pid) = *self.backend_pid.lock() {
+            return Ok(pid);
+        }
+        let pid = self.runtime.block_on(async {
+            let mut con = get_lock(self.unwrap_connection_option()?)?;
+            sqlx::query_scalar::<_, i32>("SELECT pg_backend_pid()")
+                .fetch_one(con.deref_mut())
+                .await
+                .map_err(mlua::Error::external)
+        })?;
+        *self.backend_pid.lock() = Some(pid);
+        Ok(pid)
+    }
+    ///Gets what is needed to cancel a query of this connection once it takes longer than the timeout.
+    ///Returns None if there is no timeout.
+    pub(crate) fn canceller(&self, timeout: Option<f64>) -> Result<Option<Canceller>, mlua::Error> {
+        let timeout = match timeout {
+            Some(x) => seconds_to_duration("timeout", x)?,
+            None => return Ok(None),
+        };
+        //the pid has to be known before the query starts, as the query locks the connection
+        let pid = self.get_backend_pid()?;
+        Ok(Some(Canceller {
+            connection: self.unwrap_connection_option()?.clone(),
+            connect_options: self.connect_options.clone(),
+            pid,
+            timeout,
+        }))
+    }
+    ///Runs the given query future. If it takes longer than the timeout, the query gets cancelled on the server.
+    pub(crate) fn block_on_with_timeout<T>(
+        &self,
+        timeout: Option<f64>,
+        query: impl Future<Output = Result<T, mlua::Error>>,
+    ) -> Result<T, mlua::Error> {
+        match self.canceller(timeout)? {
+            Some(canceller) => self.runtime.block_on(canceller.with_timeout(query)),
+            None => self.runtime.block_on(query),
         }
     }
 }
@@ -270,9 +1246,23 @@ impl TealData for LuaConnection<'_> {
             FunctionParams with
             query: String,
             params: QueryParamCollection,
+            timeout: Option<f64>,
         );
 
         methods.document_type("A single database connection");
+        methods.document_type("The connection gets closed once it is garbage collected, or when the function that received it ends. On lua 5.4 it can also be marked as `<close>` to close it as soon as it goes out of scope.");
+        methods.document_type("");
+        methods.document_type("The parameters of a query can either be given by position, using `$1`, `$2`, etc in the query, or by name using `:name`.");
+        methods.document_type("Once a query uses `:name`, the parameters have to be given by name. Every name in the query needs a value and every value needs to be used by the query.");
+        methods.document_type(
+            "Because lua tables can't store nil, use `tealsql.null` to pass NULL as a parameter.",
+        );
+        methods.document_type("`:` inside strings (including `E'...'` strings), quoted identifiers and comments, as well as casts like `::text` and array slices like `arr[1:n]` are left alone. This also means that named parameters can't be used between `[]`.");
+        methods.document_type(
+            "```teal_lua
+local res = con:fetch_one(\"SELECT * FROM some_table WHERE id = :id\", {id = 1})
+```",
+        );
 
         methods.document("Fetches 1 or 0 results from the database");
         methods.document("## Params:");
@@ -280,21 +1270,19 @@ impl TealData for LuaConnection<'_> {
         methods.document(
             "- params: An array (table) containing the parameters that this function needs",
         );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "fetch_optional",
-            |_, this, FunctionParams { query, mut params }| {
-                let (query, mut v) = this
-                    .runtime
-                    .block_on(this.add_params(&query, &mut params))?;
-                let x = this
-                    .runtime
-                    .block_on(query.fetch_optional(v.deref_mut()))
-                    .map_err(mlua::Error::external);
-                match x {
-                    Ok(Some(x)) => Ok(Some(LuaRow::from(x))),
-                    Ok(None) => Ok(None),
-                    Err(x) => Err(x),
-                }
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_optional(query, params)
+                })
             },
         );
         methods.document("Fetches all results into a table");
@@ -303,23 +1291,18 @@ impl TealData for LuaConnection<'_> {
         methods.document(
             "- params: An array (table) containing the parameters that this function needs",
         );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "fetch_all",
-            |_, this, FunctionParams { query, mut params }| {
-                this.runtime.block_on(async move {
-                    let (query, mut v) = this.add_params(&query, &mut params).await?;
-
-                    let mut stream = query.fetch(v.deref_mut());
-                    let mut items = Vec::new();
-                    loop {
-                        let next = stream.next().await;
-                        match next {
-                            Some(Ok(x)) => items.push(LuaRow::from(x)),
-                            Some(Err(x)) => return Err(mlua::Error::external(x)),
-                            None => break,
-                        }
-                    }
-                    Ok(items)
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_all(query, params)
                 })
             },
         );
@@ -329,6 +1312,7 @@ impl TealData for LuaConnection<'_> {
             query: String,
             params: QueryParamCollection,
             chunk_count: Option<usize>,
+            timeout: Option<f64>,
         );
         methods.document("Runs a thread in the background that fetches all results. Allowing you to consume the results in batches, or do other things while the query is being executed");
         methods.document("# Params:");
@@ -337,79 +1321,466 @@ impl TealData for LuaConnection<'_> {
             "- params: An array (table) containing the parameters that this function needs",
         );
         methods.document("- chunk_count: How big the batches are that will be returned from the background thread to the main one. Higher batch count may improve performance");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and the error is returned from the stream");
         methods.add_method(
             "fetch_all_async",
             |_,
              this,
              FunctionParamsWithCount {
-                 query,
+                 mut query,
                  mut params,
                  chunk_count,
+                 timeout,
              }| {
                 let chunk_count = chunk_count.unwrap_or(100).max(1);
                 let connection = this.unwrap_connection_option()?.clone();
                 let runtime = this.runtime.clone();
-                let iter = Iter::<tealr::mlu::mlua::Value>::from_func(move |sender| {
-                    move || {
-                        runtime.block_on(async {
-                            match add_params(&connection, &query, &mut params).await {
-                                Ok((query, mut con)) => {
-                                    let mut stream = query
-                                        .fetch(con.deref_mut())
-                                        .map(|v| match v {
-                                            Ok(x) => crate::iter::AsyncMessage::Value(x),
-                                            Err(x) => crate::iter::AsyncMessage::Error(x),
-                                        })
-                                        .chunks(chunk_count)
-                                        .map(|v| sender.send(v));
-                                    while let Some(a) = stream.next().await {
-                                        match a {
-                                            Ok(()) => (),
-                                            Err(_) => break,
-                                        }
-                                    }
-                                }
-                                Err(x) => {
-                                    if let mlua::Error::ExternalError(x) = x {
-                                        let y = vec![crate::iter::AsyncMessage::DynError(x)];
-                                        //only way that this can fail if is the receiver is already gone.
-                                        //in which case, we don't really care about what happens
-                                        let _ = sender.send(y);
-                                    }
-                                }
-                            }
-                        });
-                        drop(sender);
-                    }
-                });
+                let canceller = this.canceller(timeout)?;
+                let keep_nulls = this.keep_nulls();
+                let log = this.start_streamed(&query, &params);
+                let iter = Iter::<mlua::Value>::from_query(
+                    runtime,
+                    canceller,
+                    chunk_count,
+                    keep_nulls,
+                    log,
+                    move |rows| async move {
+                        let (query, mut con) =
+                            add_params(&connection, &mut query, &mut params).await?;
+                        rows.send_all(query.fetch(con.deref_mut())).await
+                    },
+                );
                 Ok(iter)
             },
         );
-        methods.document("Fetches exactly 1 value from the database.");
+        tealr::mlu::create_named_parameters!(
+            RowsParams with
+            query: String,
+            params: QueryParamCollection,
+        );
+        methods.document("Fetches the results one at a time, only when the next one is needed. Meant to be used in a generic `for` loop.");
+        methods.document("Unlike `fetch_all`, the results are not all kept in memory and unlike `fetch_all_async`, no thread is started.");
+        methods.document("The connection can not be used for other queries until every row is read or the iterator gets closed.");
+        methods.document("On lua 5.4, the iterator is also returned as the closing value of the loop. So leaving the loop early, like with `break`, closes it as well.");
         methods.document("## Params:");
         methods.document("- query: The query string that needs to be executed");
         methods.document(
             "- params: An array (table) containing the parameters that this function needs",
         );
-        methods.add_method("execute", |_, this, FunctionParams { query, params }| {
-            this.runtime.block_on(this.execute(query, params))
+        methods.document(
+            "```teal_lua
+for row in con:rows(\"SELECT generate_series(1, $1) AS x\", {1000000}) do
+    print(row.x)
+end
+```",
+        );
+        methods.add_method("rows", |lua, this, RowsParams { query, params }| {
+            let iter = RowIter::new(
+                this.unwrap_connection_option()?.clone(),
+                &this.row_iter,
+                this.runtime.clone(),
+                this.keep_nulls(),
+                this.start_streamed(&query, &params),
+                query,
+                params,
+            )?;
+            let func = tealr::mlu::TypedFunction::from_rust(
+                |_, iter: mlua::UserDataRef<RowIter>| iter.next(),
+                lua,
+            )?;
+            //the iterator is the closing value as well, so leaving the loop early closes it on lua 5.4
+            Ok((func, iter.clone(), mlua::Value::Nil, iter))
         });
+        methods.document("Declares a cursor on the server for the given query, so its results can be read in batches.");
+        methods
+            .document("Other queries can still run on this connection while the cursor is open.");
+        methods.document("Cursors only exist inside a transaction. If this connection is not inside one, a transaction is started that gets committed once the cursor is closed.");
         methods.document("## Params:");
         methods.document("- query: The query string that needs to be executed");
         methods.document(
             "- params: An array (table) containing the parameters that this function needs",
         );
+        methods.document(
+            "```teal_lua
+local cursor = con:cursor(\"SELECT * FROM some_table\", {})
+local rows = cursor:fetch(1000)
+while #rows > 0 do
+    --handle the rows
+    rows = cursor:fetch(1000)
+end
+cursor:close()
+```",
+        );
+        methods.add_method("cursor", |_, this, RowsParams { query, params }| {
+            this.cursor(query, params)
+        });
+        tealr::mlu::create_named_parameters!(
+            CopyInParams with
+            query: String,
+            source: CopySource,
+            format: Option<CopyFormat>,
+        );
+        methods.document("Loads data into a table using a `COPY ... FROM STDIN` statement, which is a lot faster than inserting the rows one by one.");
+        methods.document("Returns the amount of rows that got copied.");
+        methods.document("## Params:");
+        methods.document("- query: The `COPY ... FROM STDIN` statement to run, which also decides the format of the data");
+        methods.document("- source: Where the data comes from. This can be");
+        methods.document("  - a string, that is already in the format that the query expects.");
+        methods.document("  - an array of rows, where every row is an array of values. A missing value is sent as NULL.");
+        methods.document("  - a function, that gets called until it returns nil. Every string it returns is sent as is. Using this connection inside of it throws an error.");
+        methods.document("- format: Either `text` or `csv`, defaulting to `text`. Only used to encode an array of rows and needs to match the format given in the query.");
+        methods.document(
+            "```teal_lua
+con:copy_in(\"COPY some_table (id, name) FROM STDIN WITH (FORMAT csv)\", {{1, \"a\"}, {2, \"b\"}}, \"csv\")
+```",
+        );
+        methods.add_method(
+            "copy_in",
+            |_,
+             this,
+             CopyInParams {
+                 query,
+                 source,
+                 format,
+             }| {
+                this.log_query(query, Default::default(), |query, _| {
+                    this.copy_in(query, source, format)
+                })
+            },
+        );
+        methods.document("Exports data using a `COPY ... TO STDOUT` statement, without loading the whole result in memory.");
+        methods.document("The format of the data is decided by the query, so csv, text and binary are all supported.");
+        methods.document("## Params:");
+        methods.document("- query: The `COPY ... TO STDOUT` statement to run");
+        methods.document("- target: Either a function that gets called with every chunk of data, or the path of a file to write the data to. The file only gets created once the query is accepted. Using this connection inside the function throws an error.");
+        methods.document(
+            "```teal_lua
+con:copy_out(\"COPY some_table TO STDOUT WITH (FORMAT csv, HEADER)\", \"./export.csv\")
+```",
+        );
+        methods.add_method(
+            "copy_out",
+            |lua, this, (query, target): (String, CopyTarget)| {
+                this.log_query(query, Default::default(), |query, _| {
+                    this.copy_out(lua, query, target)
+                })
+            },
+        );
+        methods.document("Runs multiple statements from a single call, so the results of all of them come back together.");
+        methods.document("Statements without a params table that follow each other are sent to the database together, so they only cost a single round trip. Statements with parameters are sent one at a time, each waiting for the previous one to finish.");
+        methods.document("Returns an array with a result for every statement. This is the rows for statements that return rows, or the amount of affected rows otherwise.");
+        methods.document("Stops at the first statement that fails. Statements that ran before it are not undone, use `begin` if that is needed.");
+        methods.document("Statements that are sent together have a few differences:");
+        methods.document("- Outside of a transaction, they run in a transaction of their own. So if one of them fails, the others that were sent with it are undone as well");
+        methods.document("- A statement that returns no rows gives the amount of affected rows (0) instead of an empty array");
+        methods.document("- Their rows are sent as text, which means that INTERVAL and MONEY columns can not be read");
+        methods.document("Give a statement an empty params table to have it sent on its own.");
+        methods.document("## Params:");
+        methods.document("- statements: An array where every item is an array containing the query and optionally its parameters. Every item has to be exactly one statement");
+        methods.document("- timeout: Optional amount of seconds the whole batch may take. If it takes longer, the running query gets cancelled and an error is thrown");
+        methods.document(
+            "```teal_lua
+local results = con:batch({
+    {\"INSERT INTO some_table (name) VALUES ($1)\", {\"a\"}},
+    {\"SELECT * FROM some_table\"},
+})
+```",
+        );
+        methods.add_method(
+            "batch",
+            |_, this, (statements, timeout): (Vec<BatchStatement>, Option<f64>)| {
+                let mut logs = Vec::new();
+                let res = this.block_on_with_timeout(timeout, this.batch(statements, &mut logs));
+                this.report_queries(logs);
+                res
+            },
+        );
+        methods.document("Sends a notification to everyone listening to the given channel.");
+        methods.document("When called inside a transaction, the notification is only send once the transaction gets committed.");
+        methods.document("## Params:");
+        methods.document("- channel: The channel to send the notification on");
+        methods.document("- payload: Optional text to send along. Defaults to an empty string");
+        methods.add_method(
+            "notify",
+            |_, this, (channel, payload): (String, Option<String>)| {
+                let params = QueryParamCollection::from_iter([
+                    (1, Input::String(channel)),
+                    (2, Input::String(payload.unwrap_or_default())),
+                ]);
+                this.run_query(
+                    "SELECT pg_notify($1, $2)".into(),
+                    params,
+                    None,
+                    |sql, params| this.execute(sql, params),
+                )
+                .map(|_| ())
+            },
+        );
+        methods.document("Sets a hook that gets called after every statement that this connection runs, or removes it when given nil.");
+        methods.document("Connections made by `begin`, cursors and prepared statements share the hook of the connection they came from.");
+        methods.document("## Params:");
+        methods.document("- hook: A table with the following fields:");
+        methods.document("  - callback: The function to call. It receives a table with `sql`, `params`, `duration` in seconds, `rows` for the amount of rows returned or affected, and `error` if the statement failed");
+        methods.document("  - on_error: Optional function that receives the error if `callback` throws one. Without it, the error is logged as a warning through the `log` crate");
+        methods.document("  - redact_params: If true, every parameter gets replaced by `\"<redacted>\"` before being passed on. Defaults to false");
+        methods.document("  - slow_query_threshold: Only call the function for statements that took at least this amount of seconds. Defaults to calling it for every statement");
+        methods
+            .document("An error thrown by the callback never changes the result of the statement.");
+        methods
+            .document("Statements of `batch` are reported together, once the whole batch is done.");
+        methods.document("Queries of `rows` and `fetch_all_async` are reported once their last row is read, the query fails or the iterator gets closed. Their `duration` includes the time spent reading the rows.");
+        methods.document("The `BEGIN` and `COMMIT` or `ROLLBACK` of `begin` are reported as well.");
+        methods.document("## Example:");
+        methods.document(
+            "```teal_lua
+con:set_on_query({
+    callback = function(log:tealsql.QueryLog)
+        print(log.sql, log.duration, log.rows, log.error)
+    end,
+    redact_params = true
+})
+```",
+        );
+        methods.add_method("set_on_query", |_, this, hook: Option<QueryHook>| {
+            this.set_query_hook(hook);
+            Ok(())
+        });
+        methods.document(
+            "Sets if NULL columns in the rows that this connection returns are kept as `tealsql.null`.",
+        );
+        methods.document("By default they are left out of the row, so there is no difference between a column that is NULL and a column that does not exist.");
+        methods.document("Only this connection is affected, together with the statements, cursors and transactions made from it.");
+        methods.document("## Params:");
+        methods.document("- keep: If NULL columns should be kept");
+        methods.add_method("set_keep_nulls", |_, this, keep: bool| {
+            this.set_keep_nulls(keep);
+            Ok(())
+        });
+        methods.document("Fetches exactly 1 value from the database.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "execute",
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.execute(query, params)
+                })
+            },
+        );
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "fetch_one",
-            |_, this, FunctionParams { query, mut params }| {
-                let (query, mut v) = this
-                    .runtime
-                    .block_on(this.add_params(&query, &mut params))?;
-                let x = this
-                    .runtime
-                    .block_on(query.fetch_one(v.deref_mut()))
-                    .map_err(mlua::Error::external)?;
-                Ok(LuaRow::from(x))
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_one(query, params)
+                })
+            },
+        );
+        methods.document("Executes the query and returns both the rows it returned and the amount of rows that were affected.");
+        methods.document("Useful for queries with a `RETURNING` clause, as both values come from the same round trip.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.document("## Example:");
+        methods.document(
+            "```teal_lua
+local rows, affected = con:execute_returning(\"UPDATE some_table SET some_column = $1 RETURNING id\", {2})
+print(affected, rows[1].id)
+```",
+        );
+        methods.add_method(
+            "execute_returning",
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.execute_returning(query, params)
+                })
+            },
+        );
+        methods.document("Same as `fetch_optional`, but the row is an array instead of a table with the column names as keys.");
+        methods.document(
+            "This keeps the order of the columns, as well as columns that share the same name.",
+        );
+        methods.document(
+            "The names of the columns are returned as the second value, even if no row was found.",
+        );
+        methods.document("Columns that are null leave a hole in the array, so use the list of column names to know how many columns there are.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "fetch_optional_positional",
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_optional_positional(query, params)
+                })
+            },
+        );
+        methods.document("Same as `fetch_one`, but the row is an array instead of a table with the column names as keys.");
+        methods.document(
+            "This keeps the order of the columns, as well as columns that share the same name.",
+        );
+        methods.document("The names of the columns are returned as the second value.");
+        methods.document("Columns that are null leave a hole in the array, so use the list of column names to know how many columns there are.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "fetch_one_positional",
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_one_positional(query, params)
+                })
+            },
+        );
+        methods.document("Same as `fetch_all`, but every row is an array instead of a table with the column names as keys.");
+        methods.document("This keeps the order of the columns, as well as columns that share the same name. It also avoids creating the same keys for every row.");
+        methods.document("The names of the columns are returned as the second value, even if no rows were found.");
+        methods.document("Columns that are null leave a hole in the array, so use the list of column names to know how many columns there are.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be executed");
+        methods.document(
+            "- params: An array (table) containing the parameters that this function needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.document("## Example:");
+        methods.document(
+            "```teal_lua
+local rows, columns = con:fetch_all_positional(\"SELECT a.id, b.id FROM a JOIN b ON a.b_id = b.id\", {})
+for _, row in ipairs(rows) do
+    for index, name in ipairs(columns) do
+        print(name, row[index])
+    end
+end
+```",
+        );
+        methods.add_method(
+            "fetch_all_positional",
+            |_,
+             this,
+             FunctionParams {
+                 query,
+                 params,
+                 timeout,
+             }| {
+                this.run_query(query, params, timeout, |query, params| {
+                    this.fetch_all_positional(query, params)
+                })
+            },
+        );
+        methods.document("Prepares the query and returns a `Statement` that can run it many times, without preparing it again.");
+        methods.document("The statement can only be used for as long as this connection can.");
+        methods.document("`:name` parameters in the query are rewritten to `$n` right away. The statement then accepts its parameters by name as well as by position.");
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be prepared");
+        methods.document("## Example:");
+        methods.document(
+            "```teal_lua
+local statement = con:prepare(\"INSERT INTO some_table (some_column) VALUES ($1)\")
+for i = 1, 10 do
+    statement:execute({i})
+end
+```",
+        );
+        methods.add_method("prepare", |_, this, query: String| {
+            let (query, names) = crate::named_params::rewrite_named_params(&query);
+            let connection = this.unwrap_connection_option()?.clone();
+            let statement = this.runtime.block_on(async {
+                let mut con = get_lock(&connection)?;
+                con.prepare(&query)
+                    .await
+                    .map(|v| Statement::to_owned(&v))
+                    .map_err(mlua::Error::external)
+            })?;
+            Ok(LuaStatement::new(
+                statement,
+                names,
+                connection,
+                this.to_static(),
+            ))
+        });
+        methods.document(
+            "Asks the database what a query needs and what it returns, without running it.",
+        );
+        methods.document("## Params:");
+        methods.document("- query: The query string that needs to be described. `:name` parameters are rewritten to `$n` first");
+        methods.document("## Returns:");
+        methods.document("A table with the following fields:");
+        methods.document("- columns: The columns that the query returns, with their name, postgres type name and if they can be null. `nullable` is nil if postgres can't tell");
+        methods.document("- parameter_types: The postgres type names of the parameters, in order");
+        methods.document("- parameter_names: The names of the `:name` parameters, in the same order as `parameter_types`. Empty if the query does not use them");
+        methods.add_method("describe", |_, this, query: String| {
+            let (query, names) = crate::named_params::rewrite_named_params(&query);
+            let connection = this.unwrap_connection_option()?;
+            let describe = this.runtime.block_on(async {
+                let mut con = get_lock(connection)?;
+                con.describe(&query).await.map_err(mlua::Error::external)
+            })?;
+            Ok(Description::new(describe, names))
+        });
+        methods.document(
+            "Runs a script that can contain multiple statements, like a schema file or fixtures.",
+        );
+        methods.document("The script is sent as is, using the simple query protocol. This means it can't have parameters.");
+        methods.document("If no transaction is active then the statements are run inside a single implicit transaction, an error in one of them rolls back all of them.");
+        methods.document("## Params:");
+        methods.document("- sql: The statements to run");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.document("## Returns:");
+        methods.document("An array with the amount of rows that each statement affected, in order");
+        methods.add_method(
+            "execute_script",
+            |_, this, (sql, timeout): (String, Option<f64>)| {
+                this.run_query(sql, Default::default(), timeout, |sql, _| {
+                    this.execute_script(sql)
+                })
             },
         );
         methods.document("Starts a new transaction.");
@@ -460,73 +1831,15 @@ end)
 
 ```
         ");
-        methods.add_method_mut(
-            "begin",
-            |_,
-             this,
-             func: tealr::mlu::TypedFunction<
-                LuaConnection,
-                (Option<bool>, tealr::mlu::mlua::Variadic<Option<crate::Res>>),
-            >| {
-                let connection = this.connection.take().ok_or_else(|| {
-                    mlua::Error::external(crate::base::Error::Custom(
-                        "Tried to use a connection that is used for a transaction.".into(),
-                    ))
-                })?;
-                let mut guard = connection.lock();
-                let con = match guard.as_mut() {
-                    Some(con) => con,
-                    None => {
-                        return Err(mlua::Error::external(crate::base::Error::Custom(
-                            "Connection already dropped".into(),
-                        )))
-                    }
-                };
-                let res = this.runtime.block_on(con.execute("BEGIN;"));
-                if let Err(x) = res {
-                    drop(guard);
-                    this.connection = Some(connection);
-                    return Err(mlua::Error::external(crate::base::Error::Sqlx(x)));
-                }
-                drop(guard);
-                let lua_con = LuaConnection::from_wrapped(connection.clone(), this.runtime.clone());
-                let res = func.call(lua_con.clone()).map(|v| match v {
-                    (None, x) => (true, x),
-                    (Some(x), y) => (x, y),
-                });
-                let mut guard = connection.lock();
-                let con = match guard.as_mut() {
-                    Some(con) => con,
-                    None => {
-                        return Err(mlua::Error::external(crate::base::Error::Custom(
-                            "Connection already dropped".into(),
-                        )))
-                    }
-                };
-
-                let action = match &res {
-                    Ok((true, _)) => "COMMIT",
-                    Ok((false, _)) => "ROLLBACK",
-                    Err(_) => "ROLLBACK",
-                };
-                let rollback_res = this.runtime.block_on(con.execute(action));
-                drop(guard);
-                this.connection = Some(connection);
-                match (res, rollback_res) {
-                    (Err(res_error), Err(rollback_error)) => Err(mlua::Error::external(
-                        crate::base::Error::DBErrorAfterHandling(rollback_error, res_error),
-                    )),
-                    (Err(res_err), _) => Err(res_err),
-                    (_, Err(x)) => Err(mlua::Error::external(crate::base::Error::Sqlx(x))),
-                    (Ok(x), Ok(_)) => Ok(x),
-                }
-            },
-        );
+        methods.add_method_mut("begin", |_, this, func: TransactionFunction| {
+            this.begin(func)
+        });
         tealr::mlu::create_named_parameters!(
             InsertParams with
             name: String,
             values: BTreeMap<String, Input>,
             needs_to_get_quoted: Option<bool>,
+            timeout: Option<f64>,
         );
         methods.document("A shorthand to run a basic insert command.");
         methods.document("# WARNING!:");
@@ -534,8 +1847,9 @@ end)
         methods.document("The values that get inserted ARE properly escaped. For these, SQL injection is NOT possible.");
         methods.document("## Parameters:");
         methods.document("- name: the table name that will be inserted into");
-        methods.document("- values: A table where the keys are the column names and the values are the values that will be inserted");
+        methods.document("- values: A table where the keys are the column names and the values are the values that will be inserted. Use `tealsql.null` to insert NULL");
         methods.document("- needs_to_get_quoted: If the table name should get quotes around it. Defaults to false, set to true if the name contains .'s");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "insert",
             |_,
@@ -544,6 +1858,7 @@ end)
                  name,
                  values,
                  needs_to_get_quoted,
+                 timeout,
              }| {
                 let name = sanitize_db_table_name(name, needs_to_get_quoted.unwrap_or(false))?;
                 let (keys, markers, values) = Self::extract_lua_to_table_fields(values, 0)?;
@@ -557,7 +1872,9 @@ end)
                         .collect::<Vec<_>>()
                         .join(",")
                 );
-                this.runtime.block_on(this.execute(sql, values))
+                this.run_query(sql, values, timeout, |sql, values| {
+                    this.execute(sql, values)
+                })
             },
         );
         tealr::mlu::create_named_parameters!(
@@ -566,6 +1883,7 @@ end)
             columns: Vec<String>,
             values: Vec<Vec<Input>>,
             needs_to_get_quoted: Option<bool>,
+            timeout: Option<f64>,
         );
         methods.document("A shorthand to run a basic bulk insert command.");
         methods.document("# WARNING!:");
@@ -576,6 +1894,7 @@ end)
         methods.document("- columns: the columns that the query will insert into");
         methods.document("- values: an table containing a table for every row. Columns are entirely decided by order");
         methods.document("- needs_to_get_quoted: If the table name should get quotes around it. Defaults to false, set to true if the name contains .'s");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "bulk_insert",
             |_,
@@ -585,6 +1904,7 @@ end)
                  columns,
                  values,
                  needs_to_get_quoted,
+                 timeout,
              }| {
                 let needs_to_get_quoted = needs_to_get_quoted.unwrap_or(false);
                 let name = sanitize_db_table_name(name, needs_to_get_quoted)?;
@@ -621,7 +1941,9 @@ end)
                     column_names.join(","),
                     rows
                 );
-                this.runtime.block_on(this.execute(sql, new_values))
+                this.run_query(sql, new_values, timeout, |sql, values| {
+                    this.execute(sql, values)
+                })
             },
         );
 
@@ -631,6 +1953,7 @@ end)
             old_values: BTreeMap<String, Input>,
             new_values: BTreeMap<String, Input>,
             needs_to_get_quoted: Option<bool>,
+            timeout: Option<f64>,
         );
         methods.document("A shorthand to run a basic update command.");
         methods.document("# WARNING!:");
@@ -638,9 +1961,10 @@ end)
         methods.document("The values that get inserted ARE properly escaped. For these, SQL injection is NOT possible.");
         methods.document("## Parameters:");
         methods.document("- name: the table name that will be inserted into");
-        methods.document("- old_values: A table used to construct the `where` part of the query. The keys are the column names and the values are the values that will be matched against");
+        methods.document("- old_values: A table used to construct the `where` part of the query. The keys are the column names and the values are the values that will be matched against. Use `tealsql.null` to match rows where the column is NULL");
         methods.document("- new_values: A table where the keys are the column names and the values are the values that this column will be updated to");
         methods.document("- needs_to_get_quoted: If the table name should get quotes around it. Defaults to false, set to true if the name contains .'s");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "update",
             |_,
@@ -650,13 +1974,13 @@ end)
                  old_values,
                  new_values,
                  needs_to_get_quoted,
+                 timeout,
              }| {
                 let name = sanitize_db_table_name(name, needs_to_get_quoted.unwrap_or(false))?;
 
-                let (old_keys, old_markers, mut old_values) =
-                    Self::extract_lua_to_table_fields(old_values, 0)?;
+                let (where_parts, mut old_values) = Self::where_clause(old_values, 0)?;
                 let (new_keys, new_markers, mut new_values) =
-                    Self::extract_lua_to_table_fields(new_values, old_markers.len())?;
+                    Self::extract_lua_to_table_fields(new_values, old_values.len())?;
                 let sql = format!(
                     "UPDATE \"{}\"
                     SET {}
@@ -669,15 +1993,12 @@ end)
                         .map(|(key, marker)| format!("{} = ${}", key, marker))
                         .collect::<Vec<_>>()
                         .join(","),
-                    old_keys
-                        .into_iter()
-                        .zip(old_markers.iter())
-                        .map(|(key, marker)| format!("{} = ${}", key, marker))
-                        .collect::<Vec<_>>()
-                        .join("\n AND ")
+                    where_parts
                 );
                 new_values.append(&mut old_values);
-                this.runtime.block_on(this.execute(sql, new_values))
+                this.run_query(sql, new_values, timeout, |sql, values| {
+                    this.execute(sql, values)
+                })
             },
         );
 
@@ -688,6 +2009,7 @@ end)
             index: String,
             to_replace: BTreeMap<String,Input>,
             needs_to_get_quoted: Option<bool>,
+            timeout: Option<f64>,
         );
         methods.document(
             "A simple shorthand to do an insert and specify how the row needs to be updated instead if there is a conflict on the given index",
@@ -700,6 +2022,7 @@ end)
         methods.document("- values: A table used to construct the `where` part of the query. The keys are the column names and the values are the values that will be matched against");
         methods.document("- new_values: A table where the keys are the column names and the values are the values that this column will be updated to");
         methods.document("- needs_to_get_quoted: If the table name should get quotes around it. Defaults to false, set to true if the name contains .'s");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method("upsert", |_, this, params: UpsertParams| {
             let UpsertParams {
                 name,
@@ -707,6 +2030,7 @@ end)
                 index,
                 to_replace,
                 needs_to_get_quoted,
+                timeout,
             } = params;
             println!("{:?}",to_replace);
             let name = sanitize_db_table_name(name, needs_to_get_quoted.unwrap_or(false))?;
@@ -730,13 +2054,14 @@ end)
                 "INSERT INTO \"{name}\" ({joined_keys}) VALUES ({markers}) ON CONFLICT ON CONSTRAINT \"{index}\" DO UPDATE SET {to_update};",
             );
             values.extend(a);
-            this.runtime.block_on(this.execute(sql, values))
+            this.run_query(sql, values, timeout, |sql, values| this.execute(sql, values))
         });
         tealr::mlu::create_named_parameters!(
             DeleteParams with
             name: String,
             check_on: BTreeMap<String, Input>,
             needs_to_get_quoted: Option<bool>,
+            timeout: Option<f64>,
         );
         methods.document("A shorthand to run a basic delete command.");
         methods.document("WARNING!:");
@@ -744,8 +2069,9 @@ end)
         methods.document("The values that get inserted ARE properly escaped. For these, SQL injection is NOT possible.");
         methods.document("Parameters:");
         methods.document("- name: the table name that will be inserted into");
-        methods.document("- old_values: A table used to construct the `where` part of the query. The keys are the column names and the values are the values that will be matched against");
+        methods.document("- old_values: A table used to construct the `where` part of the query. The keys are the column names and the values are the values that will be matched against. Use `tealsql.null` to match rows where the column is NULL");
         methods.document("- needs_to_get_quoted: If the table name should get quotes around it. Defaults to false, set to true if the name contains .'s");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
         methods.add_method(
             "delete",
             |_,
@@ -754,24 +2080,36 @@ end)
                  name,
                  check_on,
                  needs_to_get_quoted,
+                 timeout,
              }| {
                 let name = sanitize_db_table_name(name, needs_to_get_quoted.unwrap_or(false))?;
-                let (keys, markers, values) = Self::extract_lua_to_table_fields(check_on, 0)?;
-                let where_parts = keys
-                    .into_iter()
-                    .zip(markers)
-                    .map(|(key, marker)| format!("{} = ${}", key, marker))
-                    .collect::<Vec<_>>()
-                    .join("\n AND ");
+                let (where_parts, values) = Self::where_clause(check_on, 0)?;
                 let sql = format!(
                     "DELETE FROM \"{}\"
                 WHERE {};
                 ",
                     name, where_parts
                 );
-                this.runtime.block_on(this.execute(sql, values))
+                this.run_query(sql, values, timeout, |sql, values| {
+                    this.execute(sql, values)
+                })
             },
         );
+        #[cfg(feature = "lua54")]
+        {
+            methods.document(
+                "Closes the connection when it goes out of scope. Only available on lua 5.4",
+            );
+            methods.document("Does nothing while the connection is being used by a transaction.");
+            methods.add_meta_method(mlua::MetaMethod::Close, |_, this, ()| {
+                //the connection of a transaction shares its slot with the connection that started it,
+                //which still needs it to commit or roll back.
+                if this.connection.is_some() && !this.in_transaction {
+                    this.drop_con()?;
+                }
+                Ok(())
+            });
+        }
         methods.generate_help();
     }
 }
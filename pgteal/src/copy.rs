@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use shared::Input;
+use tealr::{
+    mlu::{
+        mlua::{self, FromLua, Value},
+        TypedFunction,
+    },
+    ToTypename, Type,
+};
+
+#[derive(Clone, Copy)]
+pub(crate) enum CopyFormat {
+    Text,
+    Csv,
+}
+
+impl ToTypename for CopyFormat {
+    fn to_typename() -> Type {
+        String::to_typename()
+    }
+}
+
+impl FromLua for CopyFormat {
+    fn from_lua(value: Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        let format = String::from_lua(value, lua)?;
+        match format.to_lowercase().as_str() {
+            "text" => Ok(CopyFormat::Text),
+            "csv" => Ok(CopyFormat::Csv),
+            _ => Err(mlua::Error::external(crate::base::Error::Custom(format!(
+                "Unknown copy format `{format}`. Expected `text` or `csv`."
+            )))),
+        }
+    }
+}
+
+///Where the data for `Connection:copy_in` comes from.
+pub(crate) enum CopySource {
+    ///Data that is already in the format that the `COPY` statement expects
+    Raw(Vec<u8>),
+    ///Rows that still need to be encoded. Index 0 belongs to the first column.
+    Rows(Vec<BTreeMap<usize, Input>>),
+    ///Gets called until it returns nil, every string it returns gets send as is.
+    Chunks(TypedFunction<(), Option<mlua::String>>),
+}
+
+impl ToTypename for CopySource {
+    fn to_typename() -> Type {
+        Type::Or(vec![
+            String::to_typename(),
+            Vec::<Vec<Input>>::to_typename(),
+            TypedFunction::<(), Option<String>>::to_typename(),
+        ])
+    }
+}
+
+impl FromLua for CopySource {
+    fn from_lua(value: Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(x) => Ok(CopySource::Raw(x.as_bytes().to_vec())),
+            Value::Function(x) => Ok(CopySource::Chunks(TypedFunction::from_lua(
+                Value::Function(x),
+                lua,
+            )?)),
+            Value::Table(rows) => {
+                let mut res = Vec::new();
+                for row in rows.sequence_values::<mlua::Table>() {
+                    let mut columns = BTreeMap::new();
+                    for pair in row?.pairs::<usize, Input>() {
+                        let (key, value) = pair?;
+                        if key == 0 {
+                            return Err(mlua::Error::external(crate::base::Error::Custom(
+                                "The columns of a row to copy start at index 1.".into(),
+                            )));
+                        }
+                        columns.insert(key - 1, value);
+                    }
+                    res.push(columns);
+                }
+                Ok(CopySource::Rows(res))
+            }
+            x => Err(mlua::Error::FromLuaConversionError {
+                from: x.type_name(),
+                to: "string, array of rows or function".into(),
+                message: None,
+            }),
+        }
+    }
+}
+
+///Where the data of `Connection:copy_out` goes to.
+pub(crate) enum CopyTarget {
+    ///The file at this path gets created, or truncated if it already exists.
+    Path(String),
+    ///Gets called with every chunk of data that the server sends.
+    Callback(TypedFunction<mlua::String, ()>),
+}
+
+impl ToTypename for CopyTarget {
+    fn to_typename() -> Type {
+        Type::Or(vec![
+            String::to_typename(),
+            TypedFunction::<String, ()>::to_typename(),
+        ])
+    }
+}
+
+impl FromLua for CopyTarget {
+    fn from_lua(value: Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            Value::String(x) => Ok(CopyTarget::Path(x.to_str()?.to_string())),
+            x => TypedFunction::from_lua(x, lua).map(CopyTarget::Callback),
+        }
+    }
+}
+
+///Returns None for values that need to be send as NULL.
+fn encode_value(value: Option<&Input>) -> Option<String> {
+    Some(match value? {
+        Input::Null => return None,
+        Input::Boolean(x) => if *x { "t" } else { "f" }.to_string(),
+        Input::Integer(x) => x.to_string(),
+        Input::Number(x) if x.is_nan() => "NaN".to_string(),
+        Input::Number(x) if x.is_infinite() && x.is_sign_positive() => "Infinity".to_string(),
+        Input::Number(x) if x.is_infinite() => "-Infinity".to_string(),
+        Input::Number(x) => x.to_string(),
+        Input::String(x) => x.clone(),
+        Input::Table(x) => x.0.to_string(),
+    })
+}
+
+fn push_text_field(out: &mut String, value: Option<&Input>) {
+    let value = match encode_value(value) {
+        Some(x) => x,
+        None => {
+            out.push_str("\\N");
+            return;
+        }
+    };
+    for char in value.chars() {
+        match char {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            x => out.push(x),
+        }
+    }
+}
+
+fn push_csv_field(out: &mut String, value: Option<&Input>) {
+    //an unquoted empty field is NULL, so empty strings need to be quoted
+    let value = match encode_value(value) {
+        Some(x) => x,
+        None => return,
+    };
+    let needs_quotes = value.is_empty()
+        || value == "\\."
+        || value.contains(|v| matches!(v, ',' | '"' | '\n' | '\r'));
+    if needs_quotes {
+        out.push('"');
+        out.push_str(&value.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(&value);
+    }
+}
+
+///Encodes the rows in the given format.
+///Every row gets as many columns as the longest row, missing values are send as NULL.
+pub(crate) fn encode_rows(rows: &[BTreeMap<usize, Input>], format: CopyFormat) -> Vec<u8> {
+    let column_count = rows
+        .iter()
+        .filter_map(|v| v.keys().next_back())
+        .max()
+        .map(|v| v + 1)
+        .unwrap_or(0);
+    let (delimiter, push_field): (char, fn(&mut String, Option<&Input>)) = match format {
+        CopyFormat::Text => ('\t', push_text_field),
+        CopyFormat::Csv => (',', push_csv_field),
+    };
+    let mut out = String::new();
+    for row in rows {
+        for column in 0..column_count {
+            if column != 0 {
+                out.push(delimiter);
+            }
+            push_field(&mut out, row.get(&column));
+        }
+        out.push('\n');
+    }
+    out.into_bytes()
+}
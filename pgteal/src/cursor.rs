@@ -0,0 +1,217 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tealr::{
+    mlu::{mlua, TealData},
+    KindOfType, ToTypename, Type,
+};
+
+use crate::{
+    connection::{LuaConnection, QueryParamCollection},
+    pg_row::LuaRow,
+};
+
+//used to give every cursor an unique name
+static CURSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+///The cursors that are open on a connection.
+///
+///It is shared between the connection and its cursors, so the connection can clean them up once it gets released.
+#[derive(Default)]
+pub(crate) struct OpenCursors {
+    names: Vec<String>,
+    //set when the cursors live in a transaction that they started themselves
+    owns_transaction: bool,
+}
+
+impl OpenCursors {
+    ///Forgets every cursor and returns the sql that closes them.
+    ///A transaction that the cursors started gets rolled back, which also closes the cursors inside it.
+    pub(crate) fn take_release_sql(&mut self) -> Option<String> {
+        let cursors = std::mem::take(self);
+        if cursors.owns_transaction {
+            Some("ROLLBACK;".into())
+        } else if cursors.names.is_empty() {
+            None
+        } else {
+            Some(
+                cursors
+                    .names
+                    .iter()
+                    .map(|v| format!("CLOSE {v};"))
+                    .collect(),
+            )
+        }
+    }
+}
+
+///A postgres cursor, which lets the rows of a query be read in batches while the connection stays usable.
+///
+///Cursors only live as long as the transaction they are declared in.
+///If the connection is not inside a transaction, the first cursor starts one and the last one to be closed commits it.
+///Cursors that are still open when the connection is released get closed by the connection, which rolls back that transaction instead.
+#[derive(tealr::mlu::UserData)]
+pub(crate) struct Cursor {
+    connection: LuaConnection<'static>,
+    name: String,
+}
+
+impl ToTypename for Cursor {
+    fn to_typename() -> Type {
+        Type::new_single("Cursor", KindOfType::External)
+    }
+}
+
+impl Cursor {
+    pub(crate) fn open(
+        connection: LuaConnection<'static>,
+        sql: String,
+        params: QueryParamCollection,
+    ) -> Result<Self, mlua::Error> {
+        let name = format!(
+            "pgteal_cursor_{}",
+            CURSOR_COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let cursors = connection.open_cursors().clone();
+        let needs_transaction = !connection.is_in_transaction() && !cursors.lock().owns_transaction;
+        if needs_transaction {
+            connection.run_query("BEGIN".into(), Default::default(), None, |sql, _| {
+                connection.execute_script(sql)
+            })?;
+            cursors.lock().owns_transaction = true;
+        }
+        let declared = connection.run_query(
+            format!("DECLARE {name} NO SCROLL CURSOR FOR {sql}"),
+            params,
+            None,
+            |sql, params| connection.execute(sql, params),
+        );
+        if let Err(x) = declared {
+            //the failed declare aborted the transaction, taking the other cursors in it along
+            if cursors.lock().owns_transaction {
+                *cursors.lock() = OpenCursors::default();
+                //the error of the declare is the one worth reporting
+                let _ =
+                    connection.run_query("ROLLBACK".into(), Default::default(), None, |sql, _| {
+                        connection.execute_script(sql)
+                    });
+            }
+            return Err(x);
+        }
+        cursors.lock().names.push(name.clone());
+        Ok(Self { connection, name })
+    }
+
+    fn check_open(&self) -> Result<(), mlua::Error> {
+        if self
+            .connection
+            .open_cursors()
+            .lock()
+            .names
+            .contains(&self.name)
+        {
+            Ok(())
+        } else {
+            Err(mlua::Error::external(crate::base::Error::Custom(
+                "Tried to use a cursor that is already closed.".into(),
+            )))
+        }
+    }
+
+    fn fetch(&self, count: u64) -> Result<Vec<LuaRow>, mlua::Error> {
+        self.check_open()?;
+        self.connection.run_query(
+            format!("FETCH FORWARD {count} FROM {}", self.name),
+            Default::default(),
+            None,
+            |sql, params| self.connection.fetch_all(sql, params),
+        )
+    }
+
+    fn move_by(&self, count: u64) -> Result<u64, mlua::Error> {
+        self.check_open()?;
+        self.connection.run_query(
+            format!("MOVE FORWARD {count} IN {}", self.name),
+            Default::default(),
+            None,
+            |sql, params| self.connection.execute(sql, params),
+        )
+    }
+
+    fn close(&self) -> Result<(), mlua::Error> {
+        let cursors = self.connection.open_cursors().clone();
+        let ends_transaction = {
+            let cursors = cursors.lock();
+            if !cursors.names.contains(&self.name) {
+                return Ok(());
+            }
+            cursors.owns_transaction && cursors.names.len() == 1
+        };
+        let mut sql = format!("CLOSE {};", self.name);
+        if ends_transaction {
+            sql.push_str("COMMIT;");
+        }
+        //the state only changes once the statement went through, so a failure still leaves something to release
+        let closed = self
+            .connection
+            .run_query(sql, Default::default(), None, |sql, _| {
+                self.connection.execute_script(sql)
+            });
+        match closed {
+            Ok(_) => {
+                let mut cursors = cursors.lock();
+                cursors.names.retain(|v| v != &self.name);
+                if ends_transaction {
+                    cursors.owns_transaction = false;
+                }
+                Ok(())
+            }
+            Err(x) => {
+                let owns_transaction = cursors.lock().owns_transaction;
+                if owns_transaction {
+                    //an aborted transaction can only be rolled back, which closes every cursor inside it
+                    *cursors.lock() = OpenCursors::default();
+                    //the error of the close is the one worth reporting
+                    let _ = self.connection.run_query(
+                        "ROLLBACK".into(),
+                        Default::default(),
+                        None,
+                        |sql, _| self.connection.execute_script(sql),
+                    );
+                } else {
+                    //the transaction belongs to someone else, who has to roll it back
+                    cursors.lock().names.retain(|v| v != &self.name);
+                }
+                Err(x)
+            }
+        }
+    }
+}
+
+impl TealData for Cursor {
+    fn add_methods<T: tealr::mlu::TealDataMethods<Self>>(methods: &mut T) {
+        methods.document_type("A cursor on the server, made by `Connection:cursor`.");
+        methods.document_type("It allows reading the results of a query in batches, while still running other queries on the same connection.");
+        methods.document_type("If the connection was not inside a transaction, the cursor starts one that gets committed once every cursor is closed.");
+        methods.document_type(
+            "Queries that run on the connection before then are part of that transaction.",
+        );
+        methods.document_type("Cursors that are still open when the connection is released, for example when the function given to `get_connection` ends, get closed and their transaction is rolled back.");
+
+        methods.document("Fetches the next rows from the cursor.");
+        methods.document("Returns an empty table once every row has been read.");
+        methods.document("## Params:");
+        methods.document("- count: The maximum amount of rows to fetch");
+        methods.add_method("fetch", |_, this, count: u64| this.fetch(count));
+        methods.document("Skips rows without fetching them.");
+        methods.document("Returns the amount of rows that were skipped.");
+        methods.document("## Params:");
+        methods.document("- count: The amount of rows to skip");
+        methods.add_method("move", |_, this, count: u64| this.move_by(count));
+        methods.document(
+            "Closes the cursor. If it is the last open cursor, the transaction that the cursors started gets committed.",
+        );
+        methods.document("If closing fails, for example because a query failed while the cursor was open, the transaction that the cursors started gets rolled back instead and the error is thrown.");
+        methods.add_method("close", |_, this, ()| this.close());
+        methods.generate_help();
+    }
+}
@@ -0,0 +1,65 @@
+use either::Either;
+use sqlx::{Column, Describe, Postgres, TypeInfo};
+use tealr::{
+    mlu::mlua::{self, IntoLua, Value},
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+
+use crate::statement::ColumnInfo;
+
+pub(crate) struct Description {
+    columns: Vec<ColumnInfo>,
+    parameter_types: Option<Vec<String>>,
+    parameter_names: Vec<String>,
+}
+
+impl Description {
+    pub(crate) fn new(describe: Describe<Postgres>, parameter_names: Vec<String>) -> Self {
+        let columns = describe
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(index, column)| ColumnInfo {
+                name: column.name().to_string(),
+                type_name: column.type_info().name().to_string(),
+                nullable: describe.nullable(index),
+            })
+            .collect();
+        let parameter_types = match describe.parameters() {
+            Some(Either::Left(x)) => Some(x.iter().map(|v| v.name().to_string()).collect()),
+            _ => None,
+        };
+        Self {
+            columns,
+            parameter_types,
+            parameter_names,
+        }
+    }
+}
+
+impl ToTypename for Description {
+    fn to_typename() -> Type {
+        Type::new_single("Description", KindOfType::External)
+    }
+}
+
+impl IntoLua for Description {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.set("columns", self.columns)?;
+        table.set("parameter_types", self.parameter_types)?;
+        table.set("parameter_names", self.parameter_names)?;
+        table.into_lua(lua)
+    }
+}
+
+impl tealr::TypeBody for Description {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<Vec<ColumnInfo>>("columns"));
+        a.fields
+            .push(Field::new::<Option<Vec<String>>>("parameter_types"));
+        a.fields.push(Field::new::<Vec<String>>("parameter_names"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
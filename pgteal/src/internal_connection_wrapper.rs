@@ -2,7 +2,10 @@
 
 use either::Either;
 use futures::stream::BoxStream;
-use sqlx::{postgres::PgStatement, Acquire, Database, Error, Executor, Postgres};
+use sqlx::{
+    postgres::{PgConnection, PgStatement},
+    Acquire, Database, Error, Executor, Postgres,
+};
 
 #[derive(Debug)]
 pub(crate) enum WrappedConnection {
@@ -12,6 +15,16 @@ pub(crate) enum WrappedConnection {
     //ConnectionRef(&'c mut sqlx::postgres::PgConnection),
 }
 
+impl WrappedConnection {
+    ///Gives access to the connection itself, for the parts of sqlx that are not available through `Executor`.
+    pub(crate) fn pg_connection(&mut self) -> &mut PgConnection {
+        match self {
+            WrappedConnection::PoolConnection(x) => x,
+            WrappedConnection::Connection(x) => x,
+        }
+    }
+}
+
 type FetchManyResult<Res, Row> = Result<Either<Res, Row>, Error>;
 type StreamResult<'e, Res, Row> = BoxStream<'e, FetchManyResult<Res, Row>>;
 
@@ -1,6 +1,8 @@
+use futures::{Stream, StreamExt};
 use mlua::IntoLuaMulti;
 use std::{
     collections::VecDeque,
+    future::Future,
     sync::{
         mpsc::{self, TryRecvError},
         Arc, Mutex, MutexGuard,
@@ -19,19 +21,22 @@ use tealr::{
 use sqlx::postgres::PgRow;
 
 use std::sync::mpsc::{Receiver, Sender};
+use tokio::runtime::Runtime;
 
-use crate::base::Error;
+use crate::{connection::Canceller, query_log::StreamedQuery};
 
 struct ReceiverAndCache(VecDeque<AsyncMessage>, Receiver<Vec<AsyncMessage>>);
 
 pub(crate) enum AsyncMessage {
     Value(PgRow),
-    Error(sqlx::Error),
     DynError(Arc<dyn std::error::Error + Sync + Send>),
 }
 pub(crate) struct Iter<X> {
     handle: Arc<Mutex<Option<JoinHandle<()>>>>,
     channel: Arc<Mutex<ReceiverAndCache>>,
+    keep_nulls: bool,
+    //reported once every row got read or the query failed
+    log: Arc<parking_lot::Mutex<Option<StreamedQuery>>>,
     _x: std::marker::PhantomData<fn() -> X>,
 }
 
@@ -40,6 +45,8 @@ impl<X> Clone for Iter<X> {
         Self {
             handle: self.handle.clone(),
             channel: self.channel.clone(),
+            keep_nulls: self.keep_nulls,
+            log: self.log.clone(),
             _x: self._x,
         }
     }
@@ -100,7 +107,6 @@ fn get_through_locs(
                 Some(AsyncMessage::DynError(x)) => {
                     return Err(tealr::mlu::mlua::Error::external(x))
                 }
-                Some(AsyncMessage::Error(x)) => return Err(Error::Sqlx(x).into()),
                 Some(AsyncMessage::Value(x)) => return Ok((Some(x), false)),
                 None => None,
             };
@@ -111,6 +117,46 @@ fn get_through_locs(
     }
 }
 
+///Hands the rows of a query that runs on the thread of an `Iter` over to it.
+pub(crate) struct RowSender {
+    sender: Sender<Vec<AsyncMessage>>,
+    chunk_count: usize,
+}
+
+impl RowSender {
+    ///Sends the rows in chunks, stopping early once the `Iter` is gone.
+    ///
+    ///An error ends the query instead of being send as a row, so a query that got cancelled can still be turned into a timeout.
+    pub(crate) async fn send_all(
+        &self,
+        mut rows: impl Stream<Item = Result<PgRow, sqlx::Error>> + Unpin,
+    ) -> Result<(), tealr::mlu::mlua::Error> {
+        let mut chunk = Vec::with_capacity(self.chunk_count);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(x) => chunk.push(AsyncMessage::Value(x)),
+                Err(x) => {
+                    //the rows that came before the error are still worth handing over
+                    if !chunk.is_empty() {
+                        let _ = self.sender.send(chunk);
+                    }
+                    return Err(tealr::mlu::mlua::Error::external(x));
+                }
+            }
+            if chunk.len() >= self.chunk_count {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(self.chunk_count));
+                if self.sender.send(full).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = self.sender.send(chunk);
+        }
+        Ok(())
+    }
+}
+
 tealr::mlu::create_generic!(pub(crate) Out);
 
 impl<X: ToTypename + 'static + mlua::FromLua + IntoLuaMulti + TealMultiValue> Iter<X> {
@@ -118,18 +164,62 @@ impl<X: ToTypename + 'static + mlua::FromLua + IntoLuaMulti + TealMultiValue> It
         ThreadFunc: FnOnce() + Send + 'static,
         FuncSpawner: FnOnce(Sender<Vec<AsyncMessage>>) -> ThreadFunc,
     >(
+        keep_nulls: bool,
+        log: Option<StreamedQuery>,
         func: FuncSpawner,
     ) -> Self {
         let (sender, rec) = mpsc::channel();
         let thread_func = func(sender);
         let handle = std::thread::spawn(thread_func);
-        Self::new(handle, rec)
+        Self::new(handle, rec, keep_nulls, log)
     }
 
-    pub(crate) fn new(handle: JoinHandle<()>, channel: Receiver<Vec<AsyncMessage>>) -> Self {
+    ///Runs the query on a new thread, which sends its rows to the returned `Iter`.
+    ///If there is a canceller, the query gets cancelled once it runs out of time.
+    pub(crate) fn from_query<Query, Fut>(
+        runtime: Arc<Runtime>,
+        canceller: Option<Canceller>,
+        chunk_count: usize,
+        keep_nulls: bool,
+        log: Option<StreamedQuery>,
+        query: Query,
+    ) -> Self
+    where
+        Query: FnOnce(RowSender) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), tealr::mlu::mlua::Error>>,
+    {
+        Self::from_func(keep_nulls, log, move |sender| {
+            move || {
+                runtime.block_on(async {
+                    let res = query(RowSender {
+                        sender: sender.clone(),
+                        chunk_count,
+                    });
+                    let res = match &canceller {
+                        Some(canceller) => canceller.with_timeout(res).await,
+                        None => res.await,
+                    };
+                    if let Err(tealr::mlu::mlua::Error::ExternalError(x)) = res {
+                        //this only fails if the `Iter` is already gone, so nobody is left to see the error
+                        let _ = sender.send(vec![AsyncMessage::DynError(x)]);
+                    }
+                });
+                drop(sender);
+            }
+        })
+    }
+
+    pub(crate) fn new(
+        handle: JoinHandle<()>,
+        channel: Receiver<Vec<AsyncMessage>>,
+        keep_nulls: bool,
+        log: Option<StreamedQuery>,
+    ) -> Self {
         Self {
             handle: Arc::new(Mutex::new(Some(handle))),
             channel: Arc::new(Mutex::new(ReceiverAndCache(Default::default(), channel))),
+            keep_nulls,
+            log: Arc::new(parking_lot::Mutex::new(log)),
             _x: std::marker::PhantomData,
         }
     }
@@ -156,13 +246,38 @@ impl<X: ToTypename + 'static + mlua::FromLua + IntoLuaMulti + TealMultiValue> It
         }
     }
 
+    fn row_read(&self) {
+        if let Some(log) = self.log.lock().as_mut() {
+            log.row_read();
+        }
+    }
+
+    fn report(&self, error: Option<&tealr::mlu::mlua::Error>) {
+        //taken out first, so the lock is not held while the hook runs
+        let log = self.log.lock().take();
+        if let Some(log) = log {
+            log.report(error);
+        }
+    }
+
     fn get_from_cache(&mut self, force: bool) -> Result<Option<PgRow>, tealr::mlu::mlua::Error> {
-        let (item, is_disconnected) = {
+        let res = {
             let mut lock_channel = Self::get_lock(&mut self.channel)?;
-            get_through_locs(&mut lock_channel, force)?
+            get_through_locs(&mut lock_channel, force)
+        };
+        let (item, is_disconnected) = match res {
+            Ok(x) => x,
+            Err(x) => {
+                self.report(Some(&x));
+                return Err(x);
+            }
         };
+        if item.is_some() {
+            self.row_read();
+        }
         if is_disconnected {
             self.join();
+            self.report(None);
         }
         Ok(item)
     }
@@ -174,22 +289,37 @@ impl<X: ToTypename + 'static + mlua::FromLua + IntoLuaMulti + TealMultiValue> It
         func: tealr::mlu::TypedFunction<X, Out>,
     ) -> Result<Vec<Out>, tealr::mlu::mlua::Error> {
         let mut res = Vec::new();
-        {
+        let ended = {
             let mut lock_channel = Self::get_lock(&mut self.channel)?;
             loop {
-                let (item, is_disconnected) = get_through_locs(&mut lock_channel, force)?;
+                let (item, is_disconnected) = match get_through_locs(&mut lock_channel, force) {
+                    Ok(x) => x,
+                    Err(x) => break Err(x),
+                };
                 if let Some(x) = item {
-                    let x = X::from_lua(crate::pg_row::LuaRow::from(x).into_lua(lua)?, lua)?;
+                    //`self.channel` is still borrowed, so this can not go through `row_read`
+                    if let Some(log) = self.log.lock().as_mut() {
+                        log.row_read();
+                    }
+                    let x = X::from_lua(
+                        crate::pg_row::LuaRow::new(x, self.keep_nulls).into_lua(lua)?,
+                        lua,
+                    )?;
 
                     res.push(func.call(x)?);
                 }
 
                 if is_disconnected {
-                    break;
+                    break Ok(());
                 }
             }
+        };
+        if let Err(x) = ended {
+            self.report(Some(&x));
+            return Err(x);
         }
         self.join();
+        self.report(None);
         Ok(res)
     }
 
@@ -212,9 +342,10 @@ impl<X: ToTypename + 'static + mlua::FromLua + IntoLuaMulti + TealMultiValue> It
         force: bool,
         cached: tealr::mlu::mlua::Table,
     ) -> tealr::mlu::mlua::Result<Option<X>> {
+        let keep_nulls = self.keep_nulls;
         let next = self
             .get_from_cache(force)?
-            .map(|v| crate::pg_row::LuaRow::from(v).into_lua_cached(lua, cached));
+            .map(|v| crate::pg_row::LuaRow::new(v, keep_nulls).into_lua_cached(lua, cached));
         match next {
             Some(Err(x)) => Err(x),
             Some(Ok(x)) => Ok(Some(X::from_lua(x, lua)?)),
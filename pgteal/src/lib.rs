@@ -1,10 +1,22 @@
 mod base;
+mod batch;
 mod bind_params;
+mod connect_options;
 mod connection;
+mod copy;
+mod cursor;
+mod describe;
 mod internal_connection_wrapper;
 mod iter;
+mod listener;
+mod named_params;
 mod pg_row;
 mod pool;
+mod pool_options;
+mod query_log;
+mod row_iter;
+mod runtime;
+mod statement;
 
 pub use base::Base;
 
@@ -25,8 +37,20 @@ pub(crate) fn generate_types() -> TypeWalker {
     TypeWalker::new()
         .process_type_inline::<base::Base>()
         .process_type::<crate::pool::Pool>()
+        .process_type::<crate::pool_options::PoolOptions>()
+        .process_type::<crate::connect_options::ConnectOptions>()
+        .process_type::<crate::runtime::RuntimeOptions>()
+        .process_type::<crate::statement::LuaStatement>()
+        .process_type::<crate::statement::ColumnInfo>()
+        .process_type::<crate::describe::Description>()
         .process_type::<crate::connection::LuaConnection>()
         .process_type::<crate::iter::Iter<Res>>()
+        .process_type::<crate::row_iter::RowIter>()
+        .process_type::<crate::cursor::Cursor>()
+        .process_type::<crate::listener::Listener>()
+        .process_type::<crate::listener::Notification>()
+        .process_type::<crate::query_log::QueryHook>()
+        .process_type::<crate::query_log::QueryLog>()
         .process_type::<shared::Interval>()
 }
 
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use futures::FutureExt;
+use parking_lot::Mutex;
+use sqlx::postgres::{PgConnectOptions, PgListener, PgNotification, PgPoolOptions};
+use tealr::{
+    mlu::{
+        mlua::{self, IntoLua, UserDataRef, Value},
+        TealData, TypedFunction,
+    },
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+use tokio::runtime::Runtime;
+
+use crate::pool_options::seconds_to_duration;
+
+pub(crate) struct Notification {
+    channel: String,
+    payload: String,
+    process_id: u32,
+}
+
+impl From<PgNotification> for Notification {
+    fn from(notification: PgNotification) -> Self {
+        Self {
+            channel: notification.channel().to_string(),
+            payload: notification.payload().to_string(),
+            process_id: notification.process_id(),
+        }
+    }
+}
+
+impl ToTypename for Notification {
+    fn to_typename() -> Type {
+        Type::new_single("Notification", KindOfType::External)
+    }
+}
+
+impl IntoLua for Notification {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.set("channel", self.channel)?;
+        table.set("payload", self.payload)?;
+        table.set("process_id", self.process_id)?;
+        table.into_lua(lua)
+    }
+}
+
+impl tealr::TypeBody for Notification {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<String>("channel"));
+        a.fields.push(Field::new::<String>("payload"));
+        a.fields.push(Field::new::<u32>("process_id"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
+
+#[derive(Clone, tealr::mlu::UserData)]
+pub(crate) struct Listener {
+    listener: Arc<Mutex<Option<PgListener>>>,
+    runtime: Arc<Runtime>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        //the listener holds on to a pool connection, which needs the runtime to be closed.
+        if Arc::strong_count(&self.listener) == 1 {
+            let _guard = self.runtime.enter();
+            self.listener.lock().take();
+        }
+    }
+}
+
+impl ToTypename for Listener {
+    fn to_typename() -> Type {
+        Type::new_single("Listener", KindOfType::External)
+    }
+}
+
+impl Listener {
+    pub(crate) fn connect(
+        options: PgConnectOptions,
+        channels: Vec<String>,
+        runtime: Arc<Runtime>,
+    ) -> Result<Self, mlua::Error> {
+        let listener = runtime.block_on(async {
+            //the same settings that `PgListener::connect` uses, so the connection is kept around
+            let pool = PgPoolOptions::new()
+                .max_connections(1)
+                .max_lifetime(None)
+                .idle_timeout(None)
+                .connect_with(options)
+                .await?;
+            let mut listener = PgListener::connect_with(&pool).await?;
+            listener
+                .listen_all(channels.iter().map(String::as_str))
+                .await?;
+            Ok::<_, sqlx::Error>(listener)
+        });
+        let listener = listener.map_err(crate::base::Error::from)?;
+        Ok(Self {
+            listener: Arc::new(Mutex::new(Some(listener))),
+            runtime,
+        })
+    }
+
+    fn with_listener<T>(
+        &self,
+        func: impl FnOnce(&mut PgListener) -> Result<T, sqlx::Error>,
+    ) -> Result<T, mlua::Error> {
+        let mut listener = self.listener.lock();
+        let listener = listener.as_mut().ok_or_else(|| {
+            mlua::Error::external(crate::base::Error::Custom(
+                "Tried to use a listener that is already closed.".into(),
+            ))
+        })?;
+        func(listener).map_err(|v| crate::base::Error::from(v).into())
+    }
+
+    fn recv(&self, timeout: Option<f64>) -> Result<Option<Notification>, mlua::Error> {
+        let timeout = timeout
+            .map(|v| seconds_to_duration("timeout", v))
+            .transpose()?;
+        self.with_listener(|listener| {
+            self.runtime.block_on(async {
+                match timeout {
+                    //`recv` can be cancelled without losing notifications
+                    Some(timeout) => match tokio::time::timeout(timeout, listener.recv()).await {
+                        Ok(x) => x.map(Some),
+                        Err(_) => Ok(None),
+                    },
+                    None => listener.recv().await.map(Some),
+                }
+            })
+        })
+        .map(|v| v.map(Notification::from))
+    }
+
+    fn try_recv(&self) -> Result<Option<Notification>, mlua::Error> {
+        self.with_listener(|listener| {
+            self.runtime.block_on(async {
+                //gives the runtime a chance to read what arrived on the socket, without waiting for more
+                tokio::task::yield_now().await;
+                listener.recv().now_or_never().transpose()
+            })
+        })
+        .map(|v| v.map(Notification::from))
+    }
+}
+
+impl TealData for Listener {
+    fn add_methods<T: tealr::mlu::TealDataMethods<Self>>(methods: &mut T) {
+        methods.document_type("Receives the notifications that are send with `NOTIFY` or `Connection:notify`. Made by `tealsql.listen`.");
+        methods.document_type("It uses its own connection, which gets reopened if it is lost. Notifications that are send while it is reconnecting are missed.");
+
+        methods.document("Waits for the next notification and returns it.");
+        methods.document("## Params:");
+        methods.document("- timeout: Optional amount of seconds to wait. Returns nil if no notification arrived in time. Waits forever if not given");
+        methods.add_method("recv", |_, this, timeout: Option<f64>| this.recv(timeout));
+        methods.document("Returns a notification if one has already arrived, without waiting for one. Returns nil otherwise");
+        methods.add_method("try_recv", |_, this, ()| this.try_recv());
+        methods.document("Starts listening to another channel.");
+        methods.add_method("listen", |_, this, channel: String| {
+            this.with_listener(|listener| this.runtime.block_on(listener.listen(&channel)))
+        });
+        methods.document("Stops listening to the given channel.");
+        methods.add_method("unlisten", |_, this, channel: String| {
+            this.with_listener(|listener| this.runtime.block_on(listener.unlisten(&channel)))
+        });
+        methods.document("Constructs an iterator that waits for notifications, to be used in a generic `for` loop.");
+        methods.document("## Params:");
+        methods.document("- timeout: Optional amount of seconds to wait for each notification. The loop ends once no notification arrived in time. Waits forever if not given");
+        methods.document(
+            "```teal_lua
+for notification in listener:iter(5) do
+    print(notification.channel, notification.payload)
+end
+```",
+        );
+        methods.add_method("iter", |lua, this, timeout: Option<f64>| {
+            let func = TypedFunction::from_rust(
+                move |_, this: UserDataRef<Self>| this.recv(timeout),
+                lua,
+            )?;
+            Ok((func, this.clone()))
+        });
+        methods.document("Stops listening and closes the connection of this listener.");
+        methods.add_method("close", |_, this, ()| {
+            let listener = this.listener.lock().take();
+            let _guard = this.runtime.enter();
+            drop(listener);
+            Ok(())
+        });
+        methods.generate_help();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{iter::Peekable, str::Chars};
+
+fn copy_until(chars: &mut Peekable<Chars>, out: &mut String, end: &str) {
+    let mut found = String::new();
+    for char in chars.by_ref() {
+        out.push(char);
+        found.push(char);
+        if found.ends_with(end) {
+            return;
+        }
+    }
+}
+
+//like `copy_until` for a string that ends with `'`, where a backslash escapes the next character
+fn copy_escape_string(chars: &mut Peekable<Chars>, out: &mut String) {
+    while let Some(char) = chars.next() {
+        out.push(char);
+        match char {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            }
+            '\'' if chars.peek() == Some(&'\'') => {
+                out.push('\'');
+                chars.next();
+            }
+            '\'' => return,
+            _ => (),
+        }
+    }
+}
+
+//an `E` right before a `'` that is not the end of a longer name starts an escape string like `E'it\'s'`
+fn starts_escape_string(out: &str) -> bool {
+    let mut chars = out.chars().rev();
+    matches!(chars.next(), Some('E' | 'e')) && !chars.next().is_some_and(is_name_char)
+}
+
+fn is_name_start(char: char) -> bool {
+    char.is_alphabetic() || char == '_'
+}
+
+fn is_name_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '_'
+}
+
+///Rewrites every `:name` parameter in the given sql to the `$n` placeholders that postgres understands.
+///Strings (including `E'...'` strings with backslash escapes), quoted identifiers, comments, casts like `::text`
+///and array slices like `arr[1:n]` are left alone. Because of the last one, `:name` parameters can't be used between `[]`.
+///
+///Returns the new sql together with the parameter names, where the name at index 0 belongs to `$1`.
+///A name that is used multiple times only gets a single placeholder.
+pub(crate) fn rewrite_named_params(sql: &str) -> (String, Vec<String>) {
+    let mut names: Vec<String> = Vec::new();
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut brackets = 0usize;
+    while let Some(char) = chars.next() {
+        match char {
+            '\'' if starts_escape_string(&out) => {
+                out.push(char);
+                copy_escape_string(&mut chars, &mut out);
+            }
+            //a quote inside a string is escaped by doubling it, which acts like ending and starting the string again
+            '\'' | '"' => {
+                out.push(char);
+                copy_until(&mut chars, &mut out, &char.to_string());
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                out.push(char);
+                copy_until(&mut chars, &mut out, "\n");
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                out.push(char);
+                out.push(chars.next().unwrap_or('*'));
+                copy_until(&mut chars, &mut out, "*/");
+            }
+            //dollar quoted strings like $$text$$ or $body$text$body$
+            '$' if chars.peek().is_some_and(|v| !v.is_ascii_digit()) => {
+                out.push(char);
+                let mut tag = String::from("$");
+                while let Some(&next) = chars.peek() {
+                    if !is_name_char(next) {
+                        break;
+                    }
+                    tag.push(next);
+                    out.push(next);
+                    chars.next();
+                }
+                if chars.peek() == Some(&'$') {
+                    tag.push('$');
+                    out.push('$');
+                    chars.next();
+                    copy_until(&mut chars, &mut out, &tag);
+                }
+            }
+            ':' if chars.peek() == Some(&':') => {
+                out.push_str("::");
+                chars.next();
+            }
+            '[' => {
+                brackets += 1;
+                out.push(char);
+            }
+            ']' => {
+                brackets = brackets.saturating_sub(1);
+                out.push(char);
+            }
+            ':' if brackets == 0 && chars.peek().is_some_and(|v| is_name_start(*v)) => {
+                let mut name = String::new();
+                while let Some(&next) = chars.peek() {
+                    if !is_name_char(next) {
+                        break;
+                    }
+                    name.push(next);
+                    chars.next();
+                }
+                let index = match names.iter().position(|v| v == &name) {
+                    Some(x) => x,
+                    None => {
+                        names.push(name);
+                        names.len() - 1
+                    }
+                };
+                out.push('$');
+                out.push_str(&(index + 1).to_string());
+            }
+            _ => out.push(char),
+        }
+    }
+    (out, names)
+}
@@ -1,9 +1,33 @@
 use shared::Input;
-use sqlx::{postgres::PgRow, Column, Row, ValueRef};
+use sqlx::{
+    postgres::{PgRow, PgValueRef},
+    Column, Row, ValueRef,
+};
 use tealr::{mlu::mlua, ToTypename};
 
+fn decode_value(
+    value: Result<PgValueRef<'_>, sqlx::Error>,
+    lua: &mlua::Lua,
+    keep_nulls: bool,
+) -> Result<mlua::Value, mlua::Error> {
+    match value {
+        Ok(x) => {
+            if x.is_null() && keep_nulls {
+                Ok(lua.null())
+            } else if x.is_null() {
+                Ok(mlua::Nil)
+            } else {
+                shared::TypeInformation::decode(ValueRef::to_owned(&x), lua)
+            }
+        }
+        Err(x) => Err(mlua::Error::external(x)),
+    }
+}
+
 pub(crate) struct LuaRow {
     row: PgRow,
+    //set by `Connection:set_keep_nulls` of the connection that fetched the row
+    keep_nulls: bool,
 }
 impl ToTypename for LuaRow {
     fn to_typename() -> tealr::Type {
@@ -15,27 +39,21 @@ impl ToTypename for LuaRow {
 }
 
 impl LuaRow {
+    pub(crate) fn new(row: PgRow, keep_nulls: bool) -> Self {
+        LuaRow { row, keep_nulls }
+    }
     pub fn into_lua_cached(
         self,
         lua: &tealr::mlu::mlua::Lua,
         table: mlua::Table,
     ) -> std::result::Result<mlua::Value, mlua::Error> {
         let columns = self.row.columns();
+        let keep_nulls = self.keep_nulls;
         let names = columns
             .iter()
             .map(|v| {
                 let name = v.name();
-                let value = self.row.try_get_raw(name);
-                let value = match value {
-                    Ok(x) => {
-                        if x.is_null() {
-                            mlua::Nil
-                        } else {
-                            shared::TypeInformation::decode(ValueRef::to_owned(&x), lua)?
-                        }
-                    }
-                    Err(x) => return Err(mlua::Error::external(x)),
-                };
+                let value = decode_value(self.row.try_get_raw(name), lua, keep_nulls)?;
                 Ok((name, value))
             })
             .collect::<Result<Vec<_>, _>>()?;
@@ -56,8 +74,35 @@ impl tealr::mlu::mlua::IntoLua for LuaRow {
     }
 }
 
-impl From<PgRow> for LuaRow {
-    fn from(row: PgRow) -> Self {
-        LuaRow { row }
+///A row that gets turned into an array instead of a map.
+///This keeps the order of the columns, as well as columns that share a name.
+pub(crate) struct PositionalRow {
+    row: PgRow,
+    keep_nulls: bool,
+}
+impl ToTypename for PositionalRow {
+    fn to_typename() -> tealr::Type {
+        Vec::<Input>::to_typename()
+    }
+}
+
+impl tealr::mlu::mlua::IntoLua for PositionalRow {
+    fn into_lua(
+        self,
+        lua: &tealr::mlu::mlua::Lua,
+    ) -> std::result::Result<mlua::Value, mlua::Error> {
+        let len = self.row.len();
+        let table = lua.create_table_with_capacity(len, 0)?;
+        for index in 0..len {
+            let value = decode_value(self.row.try_get_raw(index), lua, self.keep_nulls)?;
+            table.raw_set(index + 1, value)?;
+        }
+        Ok(mlua::Value::Table(table))
+    }
+}
+
+impl PositionalRow {
+    pub(crate) fn new(row: PgRow, keep_nulls: bool) -> Self {
+        PositionalRow { row, keep_nulls }
     }
 }
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use sqlx::postgres::PgPoolOptions;
+use tealr::{
+    mlu::{
+        mlua::{self, FromLua, Value},
+        TypedFunction,
+    },
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+
+use crate::{base::Error, connection::LuaConnection, query_log::QueryHook};
+
+pub(crate) fn seconds_to_duration(field: &str, seconds: f64) -> Result<Duration, mlua::Error> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| mlua::Error::FromLuaConversionError {
+        from: "number",
+        to: "Duration".into(),
+        message: Some(format!(
+            "`{field}` needs to be a positive amount of seconds. Got: {seconds}"
+        )),
+    })
+}
+
+fn optional_duration(field: &str, seconds: Option<f64>) -> Result<Option<Duration>, mlua::Error> {
+    match seconds {
+        //0 is used to disable the timeout completely
+        Some(x) if x == 0.0 => Ok(None),
+        Some(x) => seconds_to_duration(field, x).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct PoolOptions {
+    max_connections: Option<u32>,
+    min_connections: Option<u32>,
+    acquire_timeout: Option<f64>,
+    idle_timeout: Option<f64>,
+    max_lifetime: Option<f64>,
+    test_before_acquire: Option<bool>,
+    after_connect: Option<mlua::Function>,
+    before_acquire: Option<mlua::Function>,
+    on_query: Option<QueryHook>,
+    keep_nulls: Option<bool>,
+}
+
+///The lua functions that get called when a connection is taken out of the pool
+#[derive(Clone, Default)]
+pub(crate) struct PoolHooks {
+    pub(crate) after_connect: Option<mlua::Function>,
+    pub(crate) before_acquire: Option<mlua::Function>,
+    ///Set as the `on_query` hook of every connection that the pool hands out
+    pub(crate) on_query: Option<QueryHook>,
+    ///Passed to `set_keep_nulls` of every connection that the pool hands out
+    pub(crate) keep_nulls: bool,
+}
+
+impl PoolOptions {
+    pub(crate) fn to_pg_pool_options(&self) -> Result<PgPoolOptions, mlua::Error> {
+        let mut options = PgPoolOptions::new();
+        if let Some(x) = self.max_connections {
+            options = options.max_connections(x);
+        }
+        if let Some(x) = self.min_connections {
+            options = options.min_connections(x);
+        }
+        if let Some(x) = self.acquire_timeout {
+            options = options.acquire_timeout(seconds_to_duration("acquire_timeout", x)?);
+        }
+        if self.idle_timeout.is_some() {
+            options = options.idle_timeout(optional_duration("idle_timeout", self.idle_timeout)?);
+        }
+        if self.max_lifetime.is_some() {
+            options = options.max_lifetime(optional_duration("max_lifetime", self.max_lifetime)?);
+        }
+        if self.test_before_acquire.unwrap_or(true) {
+            //asking for the pid checks the connection just as well as the ping does
+            //and saves a round trip once a query needs a timeout.
+            options = options.test_before_acquire(false).before_acquire(|con, _| {
+                Box::pin(async move { crate::pool::record_backend_pid(con).await.map(|_| true) })
+            });
+        } else {
+            options = options.test_before_acquire(false);
+        }
+        if self.after_connect.is_some() {
+            //connections opened by the pool in the background can't run lua code
+            //so they would never get passed to `after_connect`.
+            if self.min_connections.unwrap_or(0) > 0 {
+                return Err(Error::Custom(
+                    "`after_connect` can not be combined with a `min_connections` above 0".into(),
+                )
+                .into());
+            }
+        }
+        options = options.after_connect(|con, _| {
+            crate::pool::mark_opened_connection();
+            Box::pin(crate::pool::record_backend_pid(con))
+        });
+        Ok(options)
+    }
+    pub(crate) fn has_after_connect(&self) -> bool {
+        self.after_connect.is_some()
+    }
+    pub(crate) fn hooks(&self) -> PoolHooks {
+        PoolHooks {
+            after_connect: self.after_connect.clone(),
+            before_acquire: self.before_acquire.clone(),
+            on_query: self.on_query.clone(),
+            keep_nulls: self.keep_nulls.unwrap_or(false),
+        }
+    }
+}
+
+impl ToTypename for PoolOptions {
+    fn to_typename() -> Type {
+        Type::new_single("PoolOptions", KindOfType::External)
+    }
+}
+
+impl FromLua for PoolOptions {
+    fn from_lua(value: Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        if let Value::Table(x) = value {
+            Ok(PoolOptions {
+                max_connections: x.get("max_connections")?,
+                min_connections: x.get("min_connections")?,
+                acquire_timeout: x.get("acquire_timeout")?,
+                idle_timeout: x.get("idle_timeout")?,
+                max_lifetime: x.get("max_lifetime")?,
+                test_before_acquire: x.get("test_before_acquire")?,
+                after_connect: x.get("after_connect")?,
+                before_acquire: x.get("before_acquire")?,
+                on_query: x.get("on_query")?,
+                keep_nulls: x.get("keep_nulls")?,
+            })
+        } else {
+            Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "PoolOptions".into(),
+                message: None,
+            })
+        }
+    }
+}
+
+impl tealr::TypeBody for PoolOptions {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<Option<u32>>("max_connections"));
+        a.fields.push(Field::new::<Option<u32>>("min_connections"));
+        a.fields.push(Field::new::<Option<f64>>("acquire_timeout"));
+        a.fields.push(Field::new::<Option<f64>>("idle_timeout"));
+        a.fields.push(Field::new::<Option<f64>>("max_lifetime"));
+        a.fields
+            .push(Field::new::<Option<bool>>("test_before_acquire"));
+        a.fields.push(Field::new::<
+            Option<TypedFunction<LuaConnection<'static>, ()>>,
+        >("after_connect"));
+        a.fields.push(Field::new::<
+            Option<TypedFunction<LuaConnection<'static>, bool>>,
+        >("before_acquire"));
+        a.fields.push(Field::new::<Option<QueryHook>>("on_query"));
+        a.fields.push(Field::new::<Option<bool>>("keep_nulls"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
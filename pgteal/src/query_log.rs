@@ -0,0 +1,261 @@
+use std::time::{Duration, Instant};
+
+use shared::Input;
+use tealr::{
+    mlu::{
+        mlua::{self, FromLua, IntoLua, Value},
+        TypedFunction,
+    },
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+
+use crate::{
+    batch::BatchResult,
+    connection::QueryParamCollection,
+    pg_row::{LuaRow, PositionalRow},
+    pool_options::seconds_to_duration,
+};
+
+///How many rows a query returned or affected, as reported to the `on_query` hook.
+pub(crate) trait RowCount {
+    fn row_count(&self) -> Option<u64>;
+}
+
+impl RowCount for () {
+    fn row_count(&self) -> Option<u64> {
+        None
+    }
+}
+impl RowCount for u64 {
+    fn row_count(&self) -> Option<u64> {
+        Some(*self)
+    }
+}
+impl RowCount for Vec<u64> {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.iter().sum())
+    }
+}
+impl RowCount for LuaRow {
+    fn row_count(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+impl<T: RowCount> RowCount for Option<T> {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.as_ref().and_then(RowCount::row_count).unwrap_or(0))
+    }
+}
+impl RowCount for Vec<LuaRow> {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
+impl RowCount for (Vec<LuaRow>, u64) {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.1)
+    }
+}
+impl RowCount for (Option<PositionalRow>, Vec<String>) {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.0.is_some() as u64)
+    }
+}
+impl RowCount for (PositionalRow, Vec<String>) {
+    fn row_count(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+impl RowCount for (Vec<PositionalRow>, Vec<String>) {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+}
+impl RowCount for BatchResult {
+    fn row_count(&self) -> Option<u64> {
+        match self {
+            BatchResult::Affected(x) => Some(*x),
+            BatchResult::Rows(x) => Some(x.len() as u64),
+        }
+    }
+}
+
+///What gets passed to the `on_query` hook once a statement is done.
+pub(crate) struct QueryLog {
+    sql: String,
+    params: QueryParamCollection,
+    duration: Duration,
+    rows: Option<u64>,
+    error: Option<String>,
+}
+
+impl ToTypename for QueryLog {
+    fn to_typename() -> Type {
+        Type::new_single("QueryLog", KindOfType::External)
+    }
+}
+
+impl IntoLua for QueryLog {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.set("sql", self.sql)?;
+        table.set("params", self.params)?;
+        table.set("duration", self.duration.as_secs_f64())?;
+        table.set("rows", self.rows)?;
+        table.set("error", self.error)?;
+        table.into_lua(lua)
+    }
+}
+
+impl tealr::TypeBody for QueryLog {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<String>("sql"));
+        a.fields.push(Field::new::<Vec<Input>>("params"));
+        a.fields.push(Field::new::<f64>("duration"));
+        a.fields.push(Field::new::<Option<u64>>("rows"));
+        a.fields.push(Field::new::<Option<String>>("error"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
+
+///A statement that started running while a hook was set.
+pub(crate) struct PendingQuery {
+    sql: String,
+    params: QueryParamCollection,
+}
+
+impl PendingQuery {
+    pub(crate) fn finish<T: RowCount, E: std::fmt::Display>(
+        self,
+        duration: Duration,
+        res: &Result<T, E>,
+    ) -> QueryLog {
+        let (rows, error) = match res {
+            Ok(x) => (x.row_count(), None),
+            Err(x) => (None, Some(x.to_string())),
+        };
+        QueryLog {
+            sql: self.sql,
+            params: self.params,
+            duration,
+            rows,
+            error,
+        }
+    }
+}
+
+///A query whose rows get read one by one, like the ones of `Connection:rows` and `fetch_all_async`.
+///It gets reported once the query is done, so the duration includes the time spent reading the rows.
+pub(crate) struct StreamedQuery {
+    hook: QueryHook,
+    pending: PendingQuery,
+    started: Instant,
+    rows: u64,
+}
+
+impl StreamedQuery {
+    pub(crate) fn row_read(&mut self) {
+        self.rows += 1;
+    }
+    pub(crate) fn report(self, error: Option<&mlua::Error>) {
+        let res = match error {
+            Some(x) => Err(x),
+            None => Ok(self.rows),
+        };
+        self.hook
+            .report(self.pending.finish(self.started.elapsed(), &res));
+    }
+}
+
+///The `on_query` hook of a connection or pool.
+#[derive(Clone)]
+pub(crate) struct QueryHook {
+    callback: mlua::Function,
+    on_error: Option<mlua::Function>,
+    redact_params: bool,
+    slow_query_threshold: Option<Duration>,
+}
+
+impl QueryHook {
+    pub(crate) fn start(&self, sql: &str, params: &QueryParamCollection) -> PendingQuery {
+        PendingQuery {
+            sql: sql.to_string(),
+            params: if self.redact_params {
+                params.redacted()
+            } else {
+                params.clone()
+            },
+        }
+    }
+    pub(crate) fn start_streamed(&self, sql: &str, params: &QueryParamCollection) -> StreamedQuery {
+        StreamedQuery {
+            hook: self.clone(),
+            pending: self.start(sql, params),
+            started: Instant::now(),
+            rows: 0,
+        }
+    }
+    ///Passes the log to the callback.
+    ///An error thrown by the callback never changes the result of the statement.
+    ///It goes to `on_error` instead, or gets logged as a warning if there is no `on_error`.
+    pub(crate) fn report(&self, log: QueryLog) {
+        if let Some(threshold) = self.slow_query_threshold {
+            if log.duration < threshold {
+                return;
+            }
+        }
+        let error = match self.callback.call::<()>(log) {
+            Ok(()) => return,
+            Err(x) => x,
+        };
+        let res = match &self.on_error {
+            Some(on_error) => on_error.call::<()>(error.to_string()),
+            None => Err(error),
+        };
+        if let Err(x) = res {
+            log::warn!("The on_query hook threw an error: {}", x);
+        }
+    }
+}
+
+impl ToTypename for QueryHook {
+    fn to_typename() -> Type {
+        Type::new_single("QueryHook", KindOfType::External)
+    }
+}
+
+impl FromLua for QueryHook {
+    fn from_lua(value: Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            Value::Table(x) => Ok(QueryHook {
+                callback: x.get("callback")?,
+                on_error: x.get("on_error")?,
+                redact_params: x.get::<Option<bool>>("redact_params")?.unwrap_or(false),
+                slow_query_threshold: x
+                    .get::<Option<f64>>("slow_query_threshold")?
+                    .map(|v| seconds_to_duration("slow_query_threshold", v))
+                    .transpose()?,
+            }),
+            x => Err(mlua::Error::FromLuaConversionError {
+                from: x.type_name(),
+                to: "QueryHook".into(),
+                message: None,
+            }),
+        }
+    }
+}
+
+impl tealr::TypeBody for QueryHook {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields
+            .push(Field::new::<TypedFunction<QueryLog, ()>>("callback"));
+        a.fields
+            .push(Field::new::<Option<TypedFunction<String, ()>>>("on_error"));
+        a.fields.push(Field::new::<Option<bool>>("redact_params"));
+        a.fields
+            .push(Field::new::<Option<f64>>("slow_query_threshold"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
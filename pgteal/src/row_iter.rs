@@ -0,0 +1,230 @@
+use std::{
+    future::Future,
+    ops::DerefMut,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver},
+    SinkExt, StreamExt,
+};
+use parking_lot::Mutex;
+use sqlx::postgres::PgRow;
+use tealr::{
+    mlu::{mlua, TealData},
+    KindOfType, ToTypename, Type,
+};
+use tokio::runtime::Runtime;
+
+use crate::{
+    connection::{add_params, missing_connection, QueryParamCollection},
+    internal_connection_wrapper::WrappedConnection,
+    pg_row::LuaRow,
+    query_log::StreamedQuery,
+};
+
+type RowFuture = Pin<Box<dyn Future<Output = Result<(), mlua::Error>>>>;
+
+///The slots of the connections that are currently lent out to a `RowIter`, so a missing connection can be explained.
+static LENT_OUT: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn slot_id(slot: &Mutex<Option<WrappedConnection>>) -> usize {
+    slot as *const _ as usize
+}
+
+///Returns true if the connection of this slot is being used by a `RowIter`.
+pub(crate) fn is_lent_out(slot: &Mutex<Option<WrappedConnection>>) -> bool {
+    LENT_OUT.lock().contains(&slot_id(slot))
+}
+
+///The query of a `RowIter`, together with the connection it borrowed.
+///
+///The `Connection` keeps a weak reference to it, so it can end the query and take its connection back once it is done with it.
+pub(crate) struct BorrowedConnection {
+    query: Option<RowFuture>,
+    connection: Arc<Mutex<Option<WrappedConnection>>>,
+    borrowed: Arc<Mutex<Option<WrappedConnection>>>,
+    //reported once the query is done, by whoever ends it
+    log: Option<StreamedQuery>,
+    runtime: Arc<Runtime>,
+}
+
+impl BorrowedConnection {
+    ///Stops the query and gives the connection back.
+    pub(crate) fn finish(&mut self) {
+        if let Some(query) = self.query.take() {
+            //the query holds the lock on the borrowed connection, so it has to go first
+            drop(query);
+            let mut connection = self.connection.lock();
+            if connection.is_none() {
+                *connection = self.borrowed.lock().take();
+            }
+            let id = slot_id(&self.connection);
+            LENT_OUT.lock().retain(|v| *v != id);
+        }
+    }
+    ///Takes the log of the query, so it can be reported after the lock on this is released.
+    pub(crate) fn take_log(&mut self) -> Option<StreamedQuery> {
+        self.log.take()
+    }
+}
+
+///Pulls the rows of a query one by one, on the thread that asks for them.
+///
+///The query runs inside a future that only gets polled while the next row is requested.
+///It hands the rows over through a channel without a buffer, so at most a single row is kept around at any time.
+///
+///While the rows are being read, the connection is moved out of the `Connection` that started the query.
+///It gets put back once every row has been read, reading the rows failed, the iterator got closed or dropped
+///or the `Connection` is done with it.
+///
+///Clones share the same query, which ends once the last of them is dropped.
+#[derive(Clone, tealr::mlu::UserData)]
+pub(crate) struct RowIter {
+    state: Arc<Mutex<BorrowedConnection>>,
+    rows: Arc<Mutex<Receiver<PgRow>>>,
+    runtime: Arc<Runtime>,
+    keep_nulls: bool,
+}
+
+impl ToTypename for RowIter {
+    fn to_typename() -> Type {
+        Type::new_single("RowIter", KindOfType::External)
+    }
+}
+
+impl RowIter {
+    pub(crate) fn new(
+        connection: Arc<Mutex<Option<WrappedConnection>>>,
+        active: &Mutex<Weak<Mutex<BorrowedConnection>>>,
+        runtime: Arc<Runtime>,
+        keep_nulls: bool,
+        log: Option<StreamedQuery>,
+        mut sql: String,
+        mut params: QueryParamCollection,
+    ) -> Result<Self, mlua::Error> {
+        let taken = connection
+            .lock()
+            .take()
+            .ok_or_else(|| missing_connection(&connection))?;
+        LENT_OUT.lock().push(slot_id(&connection));
+        let borrowed = Arc::new(Mutex::new(Some(taken)));
+        let (mut sender, rows) = mpsc::channel(0);
+        let con = borrowed.clone();
+        let query: RowFuture = Box::pin(async move {
+            let (query, mut v) = add_params(&con, &mut sql, &mut params).await?;
+            let mut stream = query.fetch(v.deref_mut());
+            while let Some(row) = stream.next().await {
+                let row = row.map_err(mlua::Error::external)?;
+                if sender.send(row).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        let state = Arc::new(Mutex::new(BorrowedConnection {
+            query: Some(query),
+            connection,
+            borrowed,
+            log,
+            runtime: runtime.clone(),
+        }));
+        *active.lock() = Arc::downgrade(&state);
+        Ok(Self {
+            state,
+            rows: Arc::new(Mutex::new(rows)),
+            runtime,
+            keep_nulls,
+        })
+    }
+
+    fn poll_row(&self, cx: &mut Context<'_>) -> Poll<Result<Option<PgRow>, mlua::Error>> {
+        let mut rows = self.rows.lock();
+        if let Poll::Ready(Some(row)) = rows.poll_next_unpin(cx) {
+            return Poll::Ready(Ok(Some(row)));
+        }
+        let mut state = self.state.lock();
+        if let Some(query) = state.query.as_mut() {
+            match query.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(res) => {
+                    state.finish();
+                    res?;
+                }
+            }
+        }
+        drop(state);
+        //the sender is gone once the query is done, so this never needs to wait
+        match rows.poll_next_unpin(cx) {
+            Poll::Ready(Some(row)) => Poll::Ready(Ok(Some(row))),
+            _ => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().finish();
+        self.report(None);
+    }
+
+    fn report(&self, error: Option<&mlua::Error>) {
+        let log = self.state.lock().take_log();
+        if let Some(log) = log {
+            log.report(error);
+        }
+    }
+
+    pub(crate) fn next(&self) -> Result<Option<LuaRow>, mlua::Error> {
+        let runtime = self.runtime.clone();
+        let row = runtime.block_on(futures::future::poll_fn(|cx| self.poll_row(cx)));
+        match &row {
+            Ok(Some(_)) => {
+                if let Some(log) = self.state.lock().log.as_mut() {
+                    log.row_read();
+                }
+            }
+            Ok(None) => self.report(None),
+            Err(x) => self.report(Some(x)),
+        }
+        Ok(row?.map(|v| LuaRow::new(v, self.keep_nulls)))
+    }
+}
+
+impl Drop for BorrowedConnection {
+    fn drop(&mut self) {
+        //if the `Connection` is already garbage collected, this drops the last reference to the connection.
+        //Giving it back to its pool then needs to happen inside the runtime.
+        let _guard = self.runtime.enter();
+        self.finish();
+    }
+}
+
+impl TealData for RowIter {
+    fn add_methods<T: tealr::mlu::TealDataMethods<Self>>(methods: &mut T) {
+        methods.document_type("Returned from connection:rows(). Reads the rows of a query one at a time, only when they are asked for.");
+        methods.document_type("The connection can not run other queries until every row is read or `close` is called.");
+        methods.document_type("Once the function that received the connection ends, the iterator gets closed so the connection can be released.");
+
+        methods.document("Returns the next row, or nil if every row has been read.");
+        methods.add_method("next", |_, this, ()| this.next());
+        methods.document("Stops reading rows and makes the connection available again.");
+        methods.document("Rows that have not been read yet are skipped.");
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+        #[cfg(feature = "lua54")]
+        {
+            methods.document(
+                "Closes the iterator when it goes out of scope. Only available on lua 5.4",
+            );
+            methods.document("`connection:rows` returns the iterator as its closing value as well, so leaving a `for` loop early closes it.");
+            methods.add_meta_method(mlua::MetaMethod::Close, |_, this, ()| {
+                this.close();
+                Ok(())
+            });
+        }
+        methods.generate_help();
+    }
+}
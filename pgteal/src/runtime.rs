@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use tealr::{
+    mlu::mlua::{self, FromLua, Lua, Value},
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::base::Error;
+
+struct SharedRuntime(Arc<Runtime>);
+
+#[derive(Clone, Default)]
+pub(crate) struct RuntimeOptions {
+    multi_threaded: Option<bool>,
+    worker_threads: Option<usize>,
+}
+
+impl RuntimeOptions {
+    fn build(&self) -> Result<Runtime, mlua::Error> {
+        let multi_threaded = self
+            .multi_threaded
+            .unwrap_or_else(|| self.worker_threads.is_some());
+        let mut builder = if multi_threaded {
+            Builder::new_multi_thread()
+        } else {
+            Builder::new_current_thread()
+        };
+        match self.worker_threads {
+            Some(0) => {
+                return Err(Error::Custom("`worker_threads` needs to be at least 1".into()).into())
+            }
+            Some(x) => {
+                builder.worker_threads(x);
+            }
+            None => (),
+        }
+        Ok(builder.enable_all().build()?)
+    }
+}
+
+impl ToTypename for RuntimeOptions {
+    fn to_typename() -> Type {
+        Type::new_single("RuntimeOptions", KindOfType::External)
+    }
+}
+
+impl FromLua for RuntimeOptions {
+    fn from_lua(value: Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        if let Value::Table(x) = value {
+            Ok(RuntimeOptions {
+                multi_threaded: x.get("multi_threaded")?,
+                worker_threads: x.get("worker_threads")?,
+            })
+        } else {
+            Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "RuntimeOptions".into(),
+                message: None,
+            })
+        }
+    }
+}
+
+impl tealr::TypeBody for RuntimeOptions {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<Option<bool>>("multi_threaded"));
+        a.fields.push(Field::new::<Option<usize>>("worker_threads"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
+
+pub(crate) fn get_runtime(lua: &Lua) -> Result<Arc<Runtime>, mlua::Error> {
+    if let Some(runtime) = lua.app_data_ref::<SharedRuntime>() {
+        return Ok(runtime.0.clone());
+    }
+    let runtime = Arc::new(RuntimeOptions::default().build()?);
+    lua.set_app_data(SharedRuntime(runtime.clone()));
+    Ok(runtime)
+}
+
+pub(crate) fn configure_runtime(lua: &Lua, options: RuntimeOptions) -> Result<(), mlua::Error> {
+    if lua.app_data_ref::<SharedRuntime>().is_some() {
+        return Err(Error::Custom(
+            "The runtime is already running. `configure_runtime` needs to be called before connecting to the database".into(),
+        )
+        .into());
+    }
+    let runtime = Arc::new(options.build()?);
+    lua.set_app_data(SharedRuntime(runtime));
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use std::{ops::DerefMut, sync::Arc};
+
+use crate::{
+    bind_params::bind_params_on,
+    connection::{
+        execute_bound, fetch_all_bound, fetch_one_bound, fetch_optional_bound, get_lock,
+        LuaConnection, QueryParamCollection,
+    },
+    internal_connection_wrapper::WrappedConnection,
+    iter::Iter,
+    pg_row::LuaRow,
+};
+use either::Either;
+use parking_lot::Mutex;
+use sqlx::{
+    postgres::{PgArguments, PgStatement},
+    query::Query,
+    Column, Postgres, Statement, TypeInfo,
+};
+use tealr::{
+    mlu::{
+        mlua::{self, IntoLua, Value},
+        TealData,
+    },
+    Field, KindOfType, RecordGenerator, ToTypename, Type,
+};
+
+#[derive(Clone)]
+pub(crate) struct ColumnInfo {
+    pub(crate) name: String,
+    pub(crate) type_name: String,
+    pub(crate) nullable: Option<bool>,
+}
+
+impl ToTypename for ColumnInfo {
+    fn to_typename() -> Type {
+        Type::new_single("ColumnInfo", KindOfType::External)
+    }
+}
+
+impl IntoLua for ColumnInfo {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("type_name", self.type_name)?;
+        table.set("nullable", self.nullable)?;
+        table.into_lua(lua)
+    }
+}
+
+impl tealr::TypeBody for ColumnInfo {
+    fn get_type_body() -> tealr::TypeGenerator {
+        let mut a = RecordGenerator::new::<Self>(false);
+        a.fields.push(Field::new::<String>("name"));
+        a.fields.push(Field::new::<String>("type_name"));
+        a.fields.push(Field::new::<Option<bool>>("nullable"));
+        tealr::TypeGenerator::Record(Box::new(a))
+    }
+}
+
+///The prepared statement itself. Unlike `LuaStatement` it can be send to the thread of `fetch_all_async`.
+#[derive(Clone)]
+struct PreparedStatement {
+    statement: Arc<PgStatement<'static>>,
+    names: Arc<Vec<String>>,
+    connection: Arc<Mutex<Option<WrappedConnection>>>,
+}
+
+#[derive(Clone, tealr::mlu::UserData)]
+pub(crate) struct LuaStatement {
+    prepared: PreparedStatement,
+    //used for the timeouts and the `on_query` hook, as the statement runs on this connection
+    lua_connection: LuaConnection<'static>,
+}
+
+impl ToTypename for LuaStatement {
+    fn to_typename() -> Type {
+        Type::new_single("Statement", KindOfType::External)
+    }
+}
+
+impl LuaStatement {
+    pub(crate) fn new(
+        statement: PgStatement<'static>,
+        names: Vec<String>,
+        connection: Arc<Mutex<Option<WrappedConnection>>>,
+        lua_connection: LuaConnection<'static>,
+    ) -> Self {
+        Self {
+            prepared: PreparedStatement {
+                statement: Arc::new(statement),
+                names: Arc::new(names),
+                connection,
+            },
+            lua_connection,
+        }
+    }
+    fn sql(&self) -> String {
+        self.prepared.statement.sql().to_string()
+    }
+}
+
+impl PreparedStatement {
+    fn bind<'a>(
+        &'a self,
+        params: &'a mut QueryParamCollection,
+    ) -> Result<Query<'a, Postgres, PgArguments>, mlua::Error> {
+        params.resolve_known_names(&self.names)?;
+        bind_params_on(
+            params,
+            self.statement.parameters().unwrap_or(Either::Right(0)),
+            self.statement.query(),
+        )
+    }
+    async fn execute(&self, mut params: QueryParamCollection) -> Result<u64, mlua::Error> {
+        let query = self.bind(&mut params)?;
+        let mut con = get_lock(&self.connection)?;
+        execute_bound(query, con.deref_mut()).await
+    }
+    async fn fetch_optional(
+        &self,
+        mut params: QueryParamCollection,
+        keep_nulls: bool,
+    ) -> Result<Option<LuaRow>, mlua::Error> {
+        let query = self.bind(&mut params)?;
+        let mut con = get_lock(&self.connection)?;
+        fetch_optional_bound(query, con.deref_mut(), keep_nulls).await
+    }
+    async fn fetch_one(
+        &self,
+        mut params: QueryParamCollection,
+        keep_nulls: bool,
+    ) -> Result<LuaRow, mlua::Error> {
+        let query = self.bind(&mut params)?;
+        let mut con = get_lock(&self.connection)?;
+        fetch_one_bound(query, con.deref_mut(), keep_nulls).await
+    }
+    async fn fetch_all(
+        &self,
+        mut params: QueryParamCollection,
+        keep_nulls: bool,
+    ) -> Result<Vec<LuaRow>, mlua::Error> {
+        let query = self.bind(&mut params)?;
+        let mut con = get_lock(&self.connection)?;
+        fetch_all_bound(query, con.deref_mut(), keep_nulls).await
+    }
+}
+
+impl TealData for LuaStatement {
+    fn add_methods<T: tealr::mlu::TealDataMethods<Self>>(methods: &mut T) {
+        methods.document_type("A prepared statement, made by `Connection:prepare`.");
+        methods.document_type("It stays bound to the connection that prepared it and can only be used while that connection is.");
+        methods.document_type("Running it skips preparing the query again, which helps when the same query is run many times.");
+
+        methods.document(
+            "Returns the sql of this statement, after `:name` parameters got rewritten to `$n`.",
+        );
+        methods.add_method("sql", |_, this, ()| Ok(this.sql()));
+        methods.document(
+            "Returns the postgres type names of the parameters, in the order they get bound.",
+        );
+        methods.document("Returns nil if postgres did not tell which types it expects.");
+        methods.add_method("parameter_types", |_, this, ()| {
+            Ok(match this.prepared.statement.parameters() {
+                Some(Either::Left(x)) => {
+                    Some(x.iter().map(|v| v.name().to_string()).collect::<Vec<_>>())
+                }
+                _ => None,
+            })
+        });
+        methods.document("Returns the names of the parameters, in the order they get bound.");
+        methods.document("This list is empty if the statement does not use `:name` parameters.");
+        methods.add_method("parameter_names", |_, this, ()| {
+            Ok(this.prepared.names.as_ref().clone())
+        });
+        methods.document("Returns the columns that this statement returns.");
+        methods.document("`nullable` is always nil, use `Connection:describe` to find out if a column can be null.");
+        methods.add_method("columns", |_, this, ()| {
+            Ok(this
+                .prepared
+                .statement
+                .columns()
+                .iter()
+                .map(|v| ColumnInfo {
+                    name: v.name().to_string(),
+                    type_name: v.type_info().name().to_string(),
+                    nullable: None,
+                })
+                .collect::<Vec<_>>())
+        });
+        methods.document("Fetches 1 or 0 results from the database");
+        methods.document("## Params:");
+        methods.document(
+            "- params: An array (table) containing the parameters that this statement needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "fetch_optional",
+            |_, this, (params, timeout): (QueryParamCollection, Option<f64>)| {
+                let keep_nulls = this.lua_connection.keep_nulls();
+                this.lua_connection
+                    .run_query(this.sql(), params, timeout, |_, params| {
+                        this.prepared.fetch_optional(params, keep_nulls)
+                    })
+            },
+        );
+        methods.document("Fetches exactly 1 value from the database.");
+        methods.document("## Params:");
+        methods.document(
+            "- params: An array (table) containing the parameters that this statement needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "fetch_one",
+            |_, this, (params, timeout): (QueryParamCollection, Option<f64>)| {
+                let keep_nulls = this.lua_connection.keep_nulls();
+                this.lua_connection
+                    .run_query(this.sql(), params, timeout, |_, params| {
+                        this.prepared.fetch_one(params, keep_nulls)
+                    })
+            },
+        );
+        methods.document("Fetches all results into a table");
+        methods.document("## Params:");
+        methods.document(
+            "- params: An array (table) containing the parameters that this statement needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "fetch_all",
+            |_, this, (params, timeout): (QueryParamCollection, Option<f64>)| {
+                let keep_nulls = this.lua_connection.keep_nulls();
+                this.lua_connection
+                    .run_query(this.sql(), params, timeout, |_, params| {
+                        this.prepared.fetch_all(params, keep_nulls)
+                    })
+            },
+        );
+        methods
+            .document("Executes the statement and returns the amount of rows that were affected");
+        methods.document("## Params:");
+        methods.document(
+            "- params: An array (table) containing the parameters that this statement needs",
+        );
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and an error is thrown");
+        methods.add_method(
+            "execute",
+            |_, this, (params, timeout): (QueryParamCollection, Option<f64>)| {
+                this.lua_connection
+                    .run_query(this.sql(), params, timeout, |_, params| {
+                        this.prepared.execute(params)
+                    })
+            },
+        );
+        methods.document("Runs a thread in the background that fetches all results. Allowing you to consume the results in batches, or do other things while the query is being executed");
+        methods.document("# Params:");
+        methods.document(
+            "- params: An array (table) containing the parameters that this statement needs",
+        );
+        methods.document("- chunk_count: How big the batches are that will be returned from the background thread to the main one. Higher batch count may improve performance");
+        methods.document("- timeout: Optional amount of seconds the query may take. If it takes longer, the query gets cancelled and the error is returned from the stream");
+        methods.add_method(
+            "fetch_all_async",
+            |_,
+             this,
+             (mut params, chunk_count, timeout): (
+                QueryParamCollection,
+                Option<usize>,
+                Option<f64>,
+            )| {
+                let chunk_count = chunk_count.unwrap_or(100).max(1);
+                let prepared = this.prepared.clone();
+                let runtime = this.lua_connection.runtime();
+                let canceller = this.lua_connection.canceller(timeout)?;
+                let keep_nulls = this.lua_connection.keep_nulls();
+                let log = this.lua_connection.start_streamed(&this.sql(), &params);
+                let iter = Iter::<Value>::from_query(
+                    runtime,
+                    canceller,
+                    chunk_count,
+                    keep_nulls,
+                    log,
+                    move |rows| async move {
+                        let query = prepared.bind(&mut params)?;
+                        let mut con = get_lock(&prepared.connection)?;
+                        rows.send_all(query.fetch(con.deref_mut())).await
+                    },
+                );
+                Ok(iter)
+            },
+        );
+        methods.generate_help();
+    }
+}
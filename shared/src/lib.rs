@@ -65,13 +65,15 @@ impl tealr::ToTypename for Table {
         tealr::mlu::mlua::Table::to_typename()
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Input {
     Table(Table),
     Boolean(bool),
     Integer(i64),
     Number(f64),
     String(String),
+    ///The `null` sentinel, which gets bound as SQL NULL
+    Null,
 }
 
 impl ToTypename for Input {
@@ -87,6 +89,8 @@ impl FromLua for Input {
             mlua::Value::Integer(i) => Input::Integer(i),
             mlua::Value::Number(n) => Input::Number(n),
             mlua::Value::String(s) => Input::String(String::from_lua(mlua::Value::String(s), lua)?),
+            //`tealsql.null`
+            mlua::Value::LightUserData(x) if x.0.is_null() => Input::Null,
             _ => {
                 return Err(mlua::Error::FromLuaConversionError {
                     from: value.type_name(),
@@ -97,6 +101,18 @@ impl FromLua for Input {
         })
     }
 }
+impl IntoLua for Input {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        match self {
+            Input::Table(x) => x.into_lua(lua),
+            Input::Boolean(x) => x.into_lua(lua),
+            Input::Integer(x) => x.into_lua(lua),
+            Input::Number(x) => x.into_lua(lua),
+            Input::String(x) => x.into_lua(lua),
+            Input::Null => Ok(lua.null()),
+        }
+    }
+}
 impl FromLuaExact for Input {
     fn from_lua_exact(
         value: mlua::Value,
@@ -364,7 +380,7 @@ impl TypeInformation {
                 query.bind(x.0)
             }
             (Some(Input::Integer(x)), TypeInformation::MONEY) => query.bind(PgMoney(x)),
-            (None, _) => query.bind::<Option<bool>>(None),
+            (None | Some(Input::Null), _) => query.bind::<Option<bool>>(None),
             (Some(Input::String(x)), TypeInformation::UUID) => Uuid::parse_str(&x)
                 .map_err(mlua::Error::external)
                 .map(|v| query.bind(v))?,
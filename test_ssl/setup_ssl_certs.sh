@@ -0,0 +1,33 @@
+#!/bin/bash
+
+# Creates a self signed certificate authority together with a server and client certificate signed by it.
+# These are used by the `db_ssl` service in docker-compose.yml and by test.tl to check that tealsql can
+# connect with `ssl_mode = "verify-full"` and a client certificate.
+#
+# Usage:
+#   ./test_ssl/setup_ssl_certs.sh
+#   docker-compose up -d db_ssl
+#   cd test_ssl && tl run test.tl (with libpgteal.so and libpgteal.d.tl copied into this directory)
+
+set -e
+
+cd "$(dirname "$0")"
+mkdir -p certs
+cd certs
+
+openssl req -new -x509 -days 365 -nodes -subj "/CN=tealsql test CA" \
+    -keyout ca.key -out ca.crt
+
+openssl req -new -nodes -subj "/CN=localhost" \
+    -keyout server.key -out server.csr
+printf "subjectAltName=DNS:localhost,IP:127.0.0.1" > server.ext
+openssl x509 -req -days 365 -in server.csr -CA ca.crt -CAkey ca.key -CAcreateserial \
+    -extfile server.ext -out server.crt
+
+openssl req -new -nodes -subj "/CN=tealsql" \
+    -keyout client.key -out client.csr
+openssl x509 -req -days 365 -in client.csr -CA ca.crt -CAkey ca.key -CAcreateserial \
+    -out client.crt
+
+rm server.csr server.ext client.csr
+chmod 644 server.key client.key